@@ -1,10 +1,20 @@
 #[compute]
 #version 450
 
-const int NUM_SHAPES = 100;
 const float EPSILON = 0.01;
+// `MAX_GROUP_DEPTH` in sdf_core
+const int MAX_GROUP_DEPTH = 8;
+// shape flags in `positions.w`, see `ShapeFlags` in sdf_core
+const uint FLAG_COLLIDE = 1u;
+const uint FLAG_TRIGGER = 8u;
+const uint FLAG_PLAYER_ONLY = 16u;
+const uint FLAG_GRENADE_ONLY = 32u;
+// `ALL_LAYERS` in sdf_core
+const uint ALL_LAYERS = 16777215u;
+// `QueryFilter::SOLID` in sdf_core
+const uvec3 FILTER_SOLID = uvec3(FLAG_COLLIDE, FLAG_TRIGGER | FLAG_PLAYER_ONLY | FLAG_GRENADE_ONLY, ALL_LAYERS);
 
-layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+layout(local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
 
 layout(set = 0, binding = 0, std430) restrict buffer PointBuffer {
   vec4 points[];
@@ -26,6 +36,108 @@ layout(set = 0, binding = 3, std430) restrict buffer DataBuffer {
 }
 data_buffer;
 
+// uniform grid over the shapes, see `ShapeGrid` in sdf_core
+layout(set = 0, binding = 4, std430) restrict buffer GridBuffer {
+  vec4 origin; // xyz grid corner, w cell size, 0.0 means there is no grid
+  vec4 size; // xyz cells per axis, w distance results are clamped to inside the grid
+  uint cells[]; // (offset, count) per cell followed by the shape indices they point at
+}
+grid_buffer;
+
+layout(set = 0, binding = 5, std430) restrict buffer ModifierBuffer {
+  vec4 modifiers[]; // vec4(rounding, operation, blend radius, collision layers), a negative blend radius uses blend_factor
+}
+modifier_buffer;
+
+layout(set = 0, binding = 6, std430) restrict buffer RotationBuffer {
+  vec4 rotations[]; // quaternion
+}
+rotation_buffer;
+
+// the shape tree flattened by `group::flatten` in sdf_core, only used while
+// num_instructions is not zero
+layout(set = 0, binding = 7, std430) restrict buffer InstructionBuffer {
+  vec4 instructions[]; // vec4(kind, shape index or operation, blend radius, unused), kind 0.0 shape, 1.0 push, 2.0 pop
+}
+instruction_buffer;
+
+layout(push_constant, std430) uniform Params {
+  uint num_points; // number of invocations that have a point to work on
+  uint num_shapes; // live shapes, packed at the front of the shape buffers
+  uint num_instructions; // zero when the scene has no groups
+  uint pad1;
+}
+params;
+
+// the scene code between <SDF Scene> and <\SDF Scene> is shared with the
+// renderer, which reads the shapes from textures and carries their colour
+// along with the distance, these are the compute shaders' side of it
+#define SCENE_INFO float
+
+vec4 shape_position(int i) {
+	return position_buffer.positions[i];
+}
+
+vec4 shape_properties(int i) {
+	return property_buffer.properties[i];
+}
+
+vec4 shape_modifiers(int i) {
+	return modifier_buffer.modifiers[i];
+}
+
+vec4 shape_rotation(int i) {
+	return rotation_buffer.rotations[i];
+}
+
+int shape_count() {
+	return int(params.num_shapes);
+}
+
+vec4 shape_instruction(int i) {
+	return instruction_buffer.instructions[i];
+}
+
+int instruction_count() {
+	return int(params.num_instructions);
+}
+
+vec4 grid_origin() {
+	return grid_buffer.origin;
+}
+
+vec4 grid_size() {
+	return grid_buffer.size;
+}
+
+int grid_value(int i) {
+	return int(grid_buffer.cells[i]);
+}
+
+float blend_factor() {
+	return data_buffer.blend_factor[0];
+}
+
+float empty_info() {
+	return 100.0;
+}
+
+float shape_info(int i, float dist) {
+	return dist;
+}
+
+float with_max_dist(float info, float max_dist) {
+	return min(info, max_dist);
+}
+
+// a query_filter is (required flags, excluded flags, layer mask), see
+// `QueryFilter` in sdf_core
+bool accepts(uvec3 query_filter, int i) {
+	uint flags = uint(shape_position(i).w);
+	uint layers = uint(shape_modifiers(i).w);
+	return (flags & query_filter.x) == query_filter.x && (flags & query_filter.y) == 0u && (layers & query_filter.z) != 0u;
+}
+
 
 // <SDF Primitives>
 float sdf_sphere(vec3 point, float r) {
@@ -36,51 +148,206 @@ float sdf_box(vec3 point, vec3 bounds) {
   vec3 q = abs(point) - bounds;
   return length(max(q, 0.0)) + min(max(q.x, max(q.y,q.z)), 0.0);
 }
+float sdf_capsule(vec3 point, float r, float h) {
+	vec3 q = point;
+	q.y -= clamp(q.y, -h, h);
+	return length(q) - r;
+}
+
+float sdf_torus(vec3 point, float major, float minor) {
+	vec2 q = vec2(length(point.xz) - major, point.y);
+	return length(q) - minor;
+}
+
+float sdf_cylinder(vec3 point, float r, float h) {
+	vec2 d = vec2(length(point.xz) - r, abs(point.y) - h);
+	return min(max(d.x, d.y), 0.0) + length(max(d, 0.0));
+}
+
+float sdf_cone(vec3 point, float r1, float h, float r2) {
+	vec2 q = vec2(length(point.xz), point.y);
+	vec2 k1 = vec2(r2, h);
+	vec2 k2 = vec2(r2 - r1, 2.0 * h);
+	vec2 ca = vec2(q.x - min(q.x, (q.y < 0.0) ? r1 : r2), abs(q.y) - h);
+	vec2 cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+	float s = (cb.x < 0.0 && ca.y < 0.0) ? -1.0 : 1.0;
+	return s * sqrt(min(dot(ca, ca), dot(cb, cb)));
+}
+
+float sdf_plane(vec3 point, vec3 normal) {
+	return dot(point, normalize(normal));
+}
 // <\SDF Primitives>
 
 // <SDF Operations>
+// rotates by a unit quaternion, conjugate it to rotate the other way
+vec3 rotate(vec3 v, vec4 q) {
+	vec3 t = 2.0 * cross(q.xyz, v);
+	return v + q.w * t + cross(q.xyz, t);
+}
+
 float smoothUnion(float dist1, float dist2, float k) {
+	if(k <= 0.0) return min(dist1, dist2);
 	float h = clamp(0.5 + 0.5 * (dist2 - dist1) / k, 0.0, 1.0);
 	return mix(dist2, dist1, h) - k * h * (1.0 - h);
 }
+
+// carves dist2 out of dist1
+float smoothSubtraction(float dist1, float dist2, float k) {
+	if(k <= 0.0) return max(dist1, -dist2);
+	float h = clamp(0.5 - 0.5 * (dist1 + dist2) / k, 0.0, 1.0);
+	return mix(dist1, -dist2, h) + k * h * (1.0 - h);
+}
+
+float smoothIntersection(float dist1, float dist2, float k) {
+	if(k <= 0.0) return max(dist1, dist2);
+	float h = clamp(0.5 - 0.5 * (dist2 - dist1) / k, 0.0, 1.0);
+	return mix(dist2, dist1, h) + k * h * (1.0 - h);
+}
 // <\SDF Operations>
 
-float shape_dist(vec3 point, vec3 position, vec4 properties) {
+// <SDF Scene>
+// evaluates the shape in its local space
+float shape_dist(vec3 point, vec3 position, vec4 rotation, vec4 properties) {
+	vec3 local = rotate(point - position, vec4(-rotation.xyz, rotation.w));
+
 	if(properties.w == 1.0) {
-		return sdf_sphere(point - position, properties.x);
+		return sdf_sphere(local, properties.x);
 	}
 
 	if(properties.w == 2.0) {
-		return sdf_box(point - position, properties.xyz);
+		return sdf_box(local, properties.xyz);
 	}
+
+	if(properties.w == 3.0) {
+		return sdf_capsule(local, properties.x, properties.y);
+	}
+
+	if(properties.w == 4.0) {
+		return sdf_torus(local, properties.x, properties.y);
+	}
+
+	if(properties.w == 5.0) {
+		return sdf_cylinder(local, properties.x, properties.y);
+	}
+
+	if(properties.w == 6.0) {
+		return sdf_cone(local, properties.x, properties.y, properties.z);
+	}
+
+	if(properties.w == 7.0) {
+		return sdf_plane(local, properties.xyz);
+	}
+
+	return 100.0;
 }
 
-float get_scene_dist(vec3 point) {
-	float output_dist = 100.0;
+SCENE_INFO apply_operation(SCENE_INFO output_info, SCENE_INFO info, float operation, float k) {
+	if(operation == 1.0) {
+		return smoothSubtraction(output_info, info, k);
+	}
+
+	if(operation == 2.0) {
+		return smoothIntersection(output_info, info, k);
+	}
+
+	return smoothUnion(output_info, info, k);
+}
+
+SCENE_INFO add_shape(SCENE_INFO output_info, vec3 point, int i, uvec3 query_filter) {
+	vec4 properties = shape_properties(i);
+	vec4 modifiers = shape_modifiers(i); // vec4(rounding, operation, blend radius, collision layers)
+	if(properties.w == 0.0 || !accepts(query_filter, i)) return output_info;
+
+	float dist = shape_dist(point, shape_position(i).xyz, shape_rotation(i), properties) - modifiers.x;
+	float k = modifiers.z < 0.0 ? max(blend_factor(), 0.0) : modifiers.z;
+
+	return apply_operation(output_info, shape_info(i, dist), modifiers.y, k);
+}
+
+// every group is combined on its own stack entry, then popped into its parent
+SCENE_INFO run_instructions(vec3 point, uvec3 query_filter) {
+	SCENE_INFO stack[MAX_GROUP_DEPTH + 1];
+	int depth = 0;
+	stack[0] = empty_info();
+
+	for(int i = 0; i < instruction_count(); i++) {
+		vec4 instruction = shape_instruction(i);
+		if(instruction.x == 1.0) {
+			depth++;
+			stack[depth] = empty_info();
+		} else if(instruction.x == 2.0) {
+			float k = instruction.z < 0.0 ? max(blend_factor(), 0.0) : instruction.z;
+			stack[depth - 1] = apply_operation(stack[depth - 1], stack[depth], instruction.y, k);
+			depth--;
+		} else {
+			stack[depth] = add_shape(stack[depth], point, int(instruction.y), query_filter);
+		}
+	}
+	return stack[0];
+}
+
+// the shapes listed in the point's grid cell, or every shape when the point is
+// outside the grid, in which case it returns false
+bool grid_cell(vec3 point, out int offset, out int count) {
+	offset = 0;
+	count = shape_count();
+	vec4 origin = grid_origin();
+	vec4 size = grid_size();
+	if(origin.w <= 0.0) return false;
+
+	vec3 cell = floor((point - origin.xyz) / origin.w);
+	if(any(lessThan(cell, vec3(0.0))) || any(greaterThanEqual(cell, size.xyz))) return false;
+
+	int index = int(cell.x + size.x * (cell.y + size.y * cell.z));
+	offset = grid_value(index * 2);
+	count = grid_value(index * 2 + 1);
+	return true;
+}
 
-	for(int i = 0; i < NUM_SHAPES; i++) {
-		if(property_buffer.properties[i].w == 0.0 || position_buffer.positions[i].w >= 1.0) continue;
-		
-		float dist = shape_dist(point, position_buffer.positions[i].xyz, property_buffer.properties[i]);
+SCENE_INFO get_scene_info(vec3 point, uvec3 query_filter) {
+	if(instruction_count() > 0) return run_instructions(point, query_filter);
 
-		output_dist = smoothUnion(output_dist, dist, data_buffer.blend_factor[0]);
+	int offset;
+	int count;
+	bool in_grid = grid_cell(point, offset, count);
+
+	// unions are applied in the first pass and carving shapes in the second, so
+	// subtractions and intersections act on every union whatever the shape order
+	SCENE_INFO output_info = empty_info();
+	for(int pass = 0; pass < 2; pass++) {
+		for(int i = 0; i < count; i++) {
+			int shape = in_grid ? grid_value(offset + i) : i;
+			if((shape_modifiers(shape).y != 0.0) != (pass == 1)) continue;
+			output_info = add_shape(output_info, point, shape, query_filter);
+		}
 	}
-	return output_dist;
+
+	if(in_grid) return with_max_dist(output_info, grid_size().w);
+	return output_info;
 }
+// <\SDF Scene>
 
-vec3 get_normal(vec3 point) {
-	float dist = get_scene_dist(point);
+float get_scene_dist(vec3 point, uvec3 query_filter) {
+	return get_scene_info(point, query_filter);
+}
+
+vec3 get_normal(vec3 point, uvec3 query_filter) {
+	float dist = get_scene_dist(point, query_filter);
 	vec2 e = vec2(EPSILON, 0.0);
 	vec3 normal = vec3(
-		get_scene_dist(point + e.xyy) - get_scene_dist(point - e.xyy),
-		get_scene_dist(point + e.yxy) - get_scene_dist(point - e.yxy),
-		get_scene_dist(point + e.yyx) - get_scene_dist(point - e.yyx));
+		get_scene_dist(point + e.xyy, query_filter) - get_scene_dist(point - e.xyy, query_filter),
+		get_scene_dist(point + e.yxy, query_filter) - get_scene_dist(point - e.yxy, query_filter),
+		get_scene_dist(point + e.yyx, query_filter) - get_scene_dist(point - e.yyx, query_filter));
 	return normal;
 }
 
 void main() {
-  point_buffer.points[gl_GlobalInvocationID.x] = vec4(
-    get_normal(point_buffer.points[gl_GlobalInvocationID.x].xyz),
-    get_scene_dist(point_buffer.points[gl_GlobalInvocationID.x].xyz)
+  uint id = gl_GlobalInvocationID.x;
+  if(id >= params.num_points) return;
+
+  point_buffer.points[id] = vec4(
+    get_normal(point_buffer.points[id].xyz, FILTER_SOLID),
+    get_scene_dist(point_buffer.points[id].xyz, FILTER_SOLID)
   );
 }
@@ -0,0 +1,36 @@
+//! Converts tab separated maps to the structured format:
+//! `cargo run -p sdf_core --bin convert_map -- map.txt [map.ron]`, writing
+//! next to the input when no output is given.
+use std::path::Path;
+use std::{env, fs, process};
+
+use sdf_core::map_file::{self, MAP_FILE_EXTENSION};
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let Some(input) = args.first() else {
+    eprintln!("usage: convert_map <map.txt> [<map.ron>]");
+    process::exit(2);
+  };
+  let output = match args.get(1) {
+    Some(output) => output.clone(),
+    None => Path::new(input)
+      .with_extension(&MAP_FILE_EXTENSION[1..])
+      .to_string_lossy()
+      .into_owned(),
+  };
+
+  let content = fs::read_to_string(input).unwrap_or_else(|e| {
+    eprintln!("{}: {}", input, e);
+    process::exit(1);
+  });
+  let map = map_file::import_tab_map(&content).unwrap_or_else(|e| {
+    eprintln!("{}", e.in_file(input));
+    process::exit(1);
+  });
+  if let Err(e) = fs::write(&output, map.to_ron()) {
+    eprintln!("{}: {}", output, e);
+    process::exit(1);
+  }
+  println!("{} shapes written to {}", map.shapes.len(), output);
+}
@@ -0,0 +1,376 @@
+use crate::math::{Vec3, Vec4};
+use crate::scene::{Scene, SHAPE_NONE};
+
+/// Cells the grid aims for per shape, capped at `MAX_CELLS`.
+const CELLS_PER_SHAPE: usize = 8;
+const MAX_CELLS: usize = 1 << 15;
+// keeps flat scenes from being split into thousands of cells along one axis
+const MAX_CELLS_PER_AXIS: f32 = 64.0;
+
+/// Uniform grid over the shape bounds, so a distance query only blends the
+/// shapes listed in the cell its point falls in.
+///
+/// A cell lists every shape whose bounds, grown by `reach` plus twice the
+/// shape's blend radius, overlap it. Any shape left out is therefore too far
+/// away for its smoothed operation to change a distance below `reach`, so
+/// queries clamp their result to `reach` and stay conservative.
+/// Points outside the grid fall back to blending every shape, and scenes with
+/// groups get an empty grid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeGrid {
+  origin: Vec3,
+  cell_size: f32,
+  dims: [usize; 3],
+  reach: f32,
+  /// `(offset, count)` per cell followed by the dense shape indices they
+  /// point at, offsets are into this same array.
+  cells: Vec<u32>,
+
+  revision: u64,
+  blend_factor: f32,
+}
+
+impl ShapeGrid {
+  pub fn build(scene: &Scene) -> Self {
+    let mut grid = Self {
+      origin: Vec3::ZERO,
+      cell_size: 0.0,
+      dims: [0; 3],
+      reach: 0.0,
+      cells: Vec::new(),
+      revision: scene.layout_revision(),
+      blend_factor: scene.blend_factor,
+    };
+    // groups blend whole subtrees together, which the per shape margins do
+    // not account for, so scenes with groups are evaluated without a grid
+    if scene.has_groups() {
+      return grid;
+    }
+
+    let bounds: Vec<Option<(Vec3, Vec3)>> = (0..scene.num_shapes())
+      .map(|index| {
+        if scene.properties()[index].w == SHAPE_NONE {
+          return None;
+        }
+        return Some(scene.shape_bounds(index));
+      })
+      .collect();
+
+    let mut min = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+    let mut num_shapes = 0;
+    // unbounded shapes like planes are listed in every cell instead
+    for (shape_min, shape_max) in bounds.iter().flatten() {
+      if !shape_min.is_finite() || !shape_max.is_finite() {
+        continue;
+      }
+      min = min.component_min(*shape_min);
+      max = max.component_max(*shape_max);
+      num_shapes += 1;
+    }
+    if num_shapes == 0 {
+      return grid;
+    }
+
+    let extent = (max - min).max(1e-3);
+    let target_cells = (num_shapes * CELLS_PER_SHAPE).min(MAX_CELLS) as f32;
+    let cell_size = ((extent.x * extent.y * extent.z) / target_cells)
+      .cbrt()
+      .max(extent.max_element() / MAX_CELLS_PER_AXIS);
+
+    // the grid is grown by `reach` so points just outside a shape still land
+    // in a cell
+    grid.reach = 2.0 * cell_size;
+    let reach = Vec3::new(grid.reach, grid.reach, grid.reach);
+    grid.origin = min - reach;
+    grid.cell_size = cell_size;
+    let size = max - min + reach * 2.0;
+    grid.dims = [
+      (size.x / cell_size).ceil().max(1.0) as usize,
+      (size.y / cell_size).ceil().max(1.0) as usize,
+      (size.z / cell_size).ceil().max(1.0) as usize,
+    ];
+
+    let ranges: Vec<Option<([usize; 3], [usize; 3])>> = bounds
+      .iter()
+      .enumerate()
+      .map(|(index, bounds)| Some(grid.cell_range(scene, index, (*bounds)?)))
+      .collect();
+
+    // counting pass, then a second pass to fill the lists so every cell's
+    // shapes stay in dense order, the order the shaders blend them in
+    let num_cells = grid.num_cells();
+    let mut counts = vec![0u32; num_cells];
+    for (low, high) in ranges.iter().flatten() {
+      grid.for_each_cell(*low, *high, |cell| counts[cell] += 1);
+    }
+
+    grid.cells = vec![0; num_cells * 2];
+    let mut offset = (num_cells * 2) as u32;
+    for (cell, count) in counts.iter().enumerate() {
+      grid.cells[cell * 2] = offset;
+      offset += count;
+    }
+    grid.cells.resize(offset as usize, 0);
+
+    for (index, range) in ranges.iter().enumerate() {
+      let Some((low, high)) = range else {
+        continue;
+      };
+      let mut cells = std::mem::take(&mut grid.cells);
+      grid.for_each_cell(*low, *high, |cell| {
+        let slot = (cells[cell * 2] + cells[cell * 2 + 1]) as usize;
+        cells[slot] = index as u32;
+        cells[cell * 2 + 1] += 1;
+      });
+      grid.cells = cells;
+    }
+    return grid;
+  }
+
+  /// Whether this grid still describes `scene`.
+  pub fn is_current(&self, scene: &Scene) -> bool {
+    return self.revision == scene.layout_revision() && self.blend_factor == scene.blend_factor;
+  }
+
+  /// Whether every cell the shape at dense `index` can affect lists it, so the
+  /// grid stays valid after the shape moved without changing its type or
+  /// modifiers.
+  pub fn lists(&self, scene: &Scene, index: usize) -> bool {
+    if scene.properties()[index].w == SHAPE_NONE {
+      return true;
+    }
+    if self.cell_size <= 0.0 {
+      return false;
+    }
+
+    let (low, high) = self.cell_range(scene, index, scene.shape_bounds(index));
+    let mut listed = true;
+    self.for_each_cell(low, high, |cell| {
+      let offset = self.cells[cell * 2] as usize;
+      let count = self.cells[cell * 2 + 1] as usize;
+      listed &= self.cells[offset..offset + count]
+        .binary_search(&(index as u32))
+        .is_ok();
+    });
+    return listed;
+  }
+
+  /// Layout shared with the shaders: `(origin.xyz, cell_size)` followed by
+  /// `(dims.xyz, reach)`. A cell size of zero means there is no grid.
+  pub fn header(&self) -> [Vec4; 2] {
+    return [
+      self.origin.extend(self.cell_size),
+      Vec4::new(
+        self.dims[0] as f32,
+        self.dims[1] as f32,
+        self.dims[2] as f32,
+        self.reach,
+      ),
+    ];
+  }
+
+  pub fn cells(&self) -> &[u32] {
+    return &self.cells;
+  }
+
+  pub fn reach(&self) -> f32 {
+    return self.reach;
+  }
+
+  pub fn num_cells(&self) -> usize {
+    return self.dims[0] * self.dims[1] * self.dims[2];
+  }
+
+  /// Dense indices of the shapes that can affect `point`, or `None` when the
+  /// point is outside the grid and every shape has to be considered.
+  pub fn candidates(&self, point: Vec3) -> Option<&[u32]> {
+    if self.cell_size <= 0.0 {
+      return None;
+    }
+
+    let local = (point - self.origin) / self.cell_size;
+    let mut cell = [0; 3];
+    for (axis, value) in [local.x, local.y, local.z].into_iter().enumerate() {
+      let value = value.floor();
+      if !(value >= 0.0 && value < self.dims[axis] as f32) {
+        return None;
+      }
+      cell[axis] = value as usize;
+    }
+
+    let index = self.cell_index(cell);
+    let offset = self.cells[index * 2] as usize;
+    let count = self.cells[index * 2 + 1] as usize;
+    return Some(&self.cells[offset..offset + count]);
+  }
+
+  /// Cells the shape's bounds overlap once grown by `reach` plus twice its
+  /// blend radius.
+  fn cell_range(
+    &self,
+    scene: &Scene,
+    index: usize,
+    (shape_min, shape_max): (Vec3, Vec3),
+  ) -> ([usize; 3], [usize; 3]) {
+    let margin = self.reach + 2.0 * scene.blend_radius(index);
+    let margin = Vec3::new(margin, margin, margin);
+    return (
+      self.clamped_cell(shape_min - margin),
+      self.clamped_cell(shape_max + margin),
+    );
+  }
+
+  fn cell_index(&self, cell: [usize; 3]) -> usize {
+    return cell[0] + self.dims[0] * (cell[1] + self.dims[1] * cell[2]);
+  }
+
+  fn clamped_cell(&self, point: Vec3) -> [usize; 3] {
+    let local = (point - self.origin) / self.cell_size;
+    let mut cell = [0; 3];
+    for (axis, value) in [local.x, local.y, local.z].into_iter().enumerate() {
+      cell[axis] = (value.floor().max(0.0) as usize).min(self.dims[axis] - 1);
+    }
+    return cell;
+  }
+
+  fn for_each_cell(&self, low: [usize; 3], high: [usize; 3], mut f: impl FnMut(usize)) {
+    for z in low[2]..=high[2] {
+      for y in low[1]..=high[1] {
+        for x in low[0]..=high[0] {
+          f(self.cell_index([x, y, z]));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parity::{sample_points, Sampler};
+  use crate::query::QueryFilter;
+  use crate::scene::{FLAGS_SOLID, OP_INTERSECT, OP_SUBTRACT, SHAPE_CUBE, SHAPE_SPHERE};
+
+  /// `count` spheres and cubes, every fifth subtracted, clipped by a small
+  /// intersecting sphere.
+  fn scattered(blend_factor: f32, count: usize) -> Scene {
+    let mut scene = Scene::with_max_shapes(blend_factor, count + 1);
+    let mut sampler = Sampler::new(3);
+    for i in 0..count {
+      let kind = if i % 2 == 0 { SHAPE_SPHERE } else { SHAPE_CUBE };
+      let position = sampler.point_in((Vec3::new(-20.0, -5.0, -20.0), Vec3::new(20.0, 5.0, 20.0)));
+      let size = sampler.point_in((Vec3::new(0.2, 0.2, 0.2), Vec3::new(2.0, 2.0, 2.0)));
+      let address = scene
+        .new_shape(
+          position.extend(FLAGS_SOLID),
+          Vec4::QUAT_IDENTITY,
+          size.extend(kind),
+          Vec4::ZERO,
+        )
+        .unwrap();
+      if i % 5 == 4 {
+        scene.set_operation(address, OP_SUBTRACT, 0.3).unwrap();
+      }
+    }
+    let clip = scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(2.0, 0.0, 0.0, SHAPE_SPHERE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+    scene.set_operation(clip, OP_INTERSECT, 0.5).unwrap();
+    return scene;
+  }
+
+  #[test]
+  fn empty_scene_has_no_grid() {
+    let grid = ShapeGrid::build(&Scene::default());
+    assert_eq!(grid.candidates(Vec3::ZERO), None);
+    assert_eq!(grid.header()[0].w, 0.0);
+  }
+
+  #[test]
+  fn cells_list_shapes_in_dense_order() {
+    let scene = scattered(0.5, 200);
+    let grid = ShapeGrid::build(&scene);
+    assert!(grid.num_cells() > 1);
+
+    for point in sample_points(&scene, 512, 4) {
+      let candidates = grid.candidates(point.xyz()).unwrap();
+      assert!(candidates.windows(2).all(|pair| pair[0] < pair[1]));
+      assert!(candidates.len() < scene.num_shapes());
+    }
+  }
+
+  #[test]
+  fn small_moves_keep_the_grid() {
+    let mut scene = scattered(0.5, 200);
+    scene.update_caches();
+    let handle = scene.handles()[0];
+    let mut shape = scene.shape(handle).unwrap();
+    let layout_revision = scene.layout_revision();
+
+    shape.position.x += 1e-3;
+    scene.set_shape(handle, &shape).unwrap();
+    assert_eq!(scene.layout_revision(), layout_revision);
+    assert!(scene.grid().is_some());
+
+    let mut exhaustive = scattered(0.5, 200);
+    exhaustive.set_shape(handle, &shape).unwrap();
+    let reach = scene.grid().unwrap().reach();
+    for point in sample_points(&scene, 1024, 6) {
+      let expected = exhaustive.get_scene_dist(point.xyz()).min(reach);
+      assert!((scene.get_scene_dist(point.xyz()) - expected).abs() < 1e-3);
+    }
+
+    shape.position.x += 100.0;
+    scene.set_shape(handle, &shape).unwrap();
+    assert_ne!(scene.layout_revision(), layout_revision);
+    assert!(scene.grid().is_none());
+  }
+
+  #[test]
+  fn surfaces_find_their_shape_in_the_grid() {
+    let exhaustive = scattered(0.5, 200);
+    let mut scene = exhaustive.clone();
+    scene.update_caches();
+    let reach = scene.grid().unwrap().reach();
+
+    for point in sample_points(&scene, 4096, 7) {
+      if exhaustive.get_scene_dist(point.xyz()) < reach {
+        assert_eq!(
+          scene.surface_shape(point.xyz(), QueryFilter::SOLID),
+          exhaustive.surface_shape(point.xyz(), QueryFilter::SOLID)
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn matches_every_shape_within_reach() {
+    for blend_factor in [0.0, 0.5, 2.0] {
+      let exhaustive = scattered(blend_factor, 200);
+      let mut scene = exhaustive.clone();
+      scene.update_caches();
+      let reach = scene.grid().unwrap().reach();
+
+      for point in sample_points(&scene, 4096, 5) {
+        let expected = exhaustive.get_scene_dist(point.xyz());
+        let actual = scene.get_scene_dist(point.xyz());
+
+        // the same near surfaces, and clamped to the reach further away
+        assert!(
+          (actual - expected.min(reach)).abs() < 1e-3,
+          "{} != {} at {:?} with blend factor {}",
+          actual,
+          expected.min(reach),
+          point,
+          blend_factor
+        );
+      }
+    }
+  }
+}
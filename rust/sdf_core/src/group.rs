@@ -0,0 +1,170 @@
+use crate::math::Vec4;
+use crate::scene::{Scene, OP_UNION};
+
+/// Deepest a group can be nested below the scene, which sizes the stack the
+/// shaders evaluate instructions with.
+pub const MAX_GROUP_DEPTH: usize = 8;
+
+pub const INSTRUCTION_SHAPE: f32 = 0.0;
+pub const INSTRUCTION_PUSH: f32 = 1.0;
+pub const INSTRUCTION_POP: f32 = 2.0;
+
+/// A node of the scene's shape tree. Its shapes and child groups are combined
+/// on their own, like a scene of their own, and the result is then combined
+/// with the group's parent through `operation`, smoothed over
+/// `blend_radius` or the scene's `blend_factor` for `BLEND_SCENE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Group {
+  /// Address of the parent group, `None` for groups directly in the scene.
+  pub parent: Option<usize>,
+  pub operation: f32,
+  pub blend_radius: f32,
+}
+
+/// The shape tree as the instruction list the shaders evaluate, each
+/// instruction is `(kind, shape index or operation, blend radius, unused)`:
+/// - `INSTRUCTION_SHAPE` combines the shape at dense index `y` into the top
+///   of the stack with the shape's own operation and blend radius
+/// - `INSTRUCTION_PUSH` starts a group with an empty distance on the stack
+/// - `INSTRUCTION_POP` combines the finished group into the one below it with
+///   operation `y`, smoothed over `z`
+///
+/// Every group lists its unions before its carving shapes and groups, so
+/// subtractions and intersections act on all of the group's unions.
+pub fn flatten(scene: &Scene) -> Vec<Vec4> {
+  // children of every group by address, the scene itself is the last entry
+  let root = scene.max_shapes();
+  let mut shapes = vec![Vec::new(); root + 1];
+  let mut groups = vec![Vec::new(); root + 1];
+  for (index, group) in scene.shape_groups().iter().enumerate() {
+    shapes[group.unwrap_or(root)].push(index);
+  }
+  for (address, group) in scene.groups() {
+    groups[group.parent.unwrap_or(root)].push(address);
+  }
+
+  let mut instructions = Vec::with_capacity(scene.num_shapes());
+  push_children(scene, root, &shapes, &groups, &mut instructions);
+  return instructions;
+}
+
+fn push_children(
+  scene: &Scene,
+  node: usize,
+  shapes: &[Vec<usize>],
+  groups: &[Vec<usize>],
+  instructions: &mut Vec<Vec4>,
+) {
+  for carving in [false, true] {
+    for index in &shapes[node] {
+      if (scene.modifiers()[*index].y != OP_UNION) == carving {
+        instructions.push(Vec4::new(INSTRUCTION_SHAPE, *index as f32, 0.0, 0.0));
+      }
+    }
+
+    for address in &groups[node] {
+      let group = scene.group(*address).unwrap();
+      if (group.operation != OP_UNION) != carving {
+        continue;
+      }
+
+      instructions.push(Vec4::new(INSTRUCTION_PUSH, 0.0, 0.0, 0.0));
+      push_children(scene, *address, shapes, groups, instructions);
+      instructions.push(Vec4::new(
+        INSTRUCTION_POP,
+        group.operation,
+        group.blend_radius,
+        0.0,
+      ));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::Vec3;
+  use crate::scene::{ShapeHandle, FLAGS_SOLID, OP_SUBTRACT, SHAPE_SPHERE};
+
+  fn sphere(scene: &mut Scene, x: f32) -> ShapeHandle {
+    return scene
+      .new_shape(
+        Vec4::new(x, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(1.0, 0.0, 0.0, SHAPE_SPHERE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+  }
+
+  #[test]
+  fn groups_are_bracketed_after_their_parent_unions() {
+    let mut scene = Scene::new(0.5);
+    let outer = scene.new_group(None, OP_SUBTRACT, 0.0).unwrap();
+    let inner = scene.new_group(Some(outer), OP_UNION, 1.0).unwrap();
+    let first = sphere(&mut scene, 0.0);
+    let second = sphere(&mut scene, 1.0);
+    sphere(&mut scene, 2.0);
+    scene.set_shape_group(first, Some(inner)).unwrap();
+    scene.set_shape_group(second, Some(outer)).unwrap();
+
+    let kinds: Vec<(f32, f32)> = flatten(&scene)
+      .iter()
+      .map(|instruction| (instruction.x, instruction.y))
+      .collect();
+    assert_eq!(
+      kinds,
+      vec![
+        (INSTRUCTION_SHAPE, 2.0),
+        (INSTRUCTION_PUSH, 0.0),
+        (INSTRUCTION_SHAPE, 1.0),
+        (INSTRUCTION_PUSH, 0.0),
+        (INSTRUCTION_SHAPE, 0.0),
+        (INSTRUCTION_POP, OP_UNION),
+        (INSTRUCTION_POP, OP_SUBTRACT),
+      ]
+    );
+  }
+
+  #[test]
+  fn groups_blend_independently() {
+    // two spheres blended softly inside a group, hard unioned with a third
+    let mut scene = Scene::new(0.0);
+    let blob = scene.new_group(None, OP_UNION, 0.0).unwrap();
+    for x in [0.0, 1.5] {
+      let address = sphere(&mut scene, x);
+      scene.set_operation(address, OP_UNION, 1.0).unwrap();
+      scene.set_shape_group(address, Some(blob)).unwrap();
+    }
+    sphere(&mut scene, 5.0);
+    scene.update_caches();
+
+    assert!(scene.get_scene_dist(Vec3::new(0.75, 1.0, 0.0)) < 0.2);
+    assert!((scene.get_scene_dist(Vec3::new(3.5, 0.0, 0.0)) - 0.5).abs() < 1e-4);
+  }
+
+  #[test]
+  fn removed_groups_hand_their_children_to_their_parent() {
+    let mut scene = Scene::new(0.5);
+    let outer = scene.new_group(None, OP_UNION, 0.0).unwrap();
+    let inner = scene.new_group(Some(outer), OP_UNION, 0.0).unwrap();
+    let address = sphere(&mut scene, 0.0);
+    scene.set_shape_group(address, Some(inner)).unwrap();
+
+    scene.remove_group(outer).unwrap();
+    assert_eq!(scene.group(inner).unwrap().parent, None);
+    scene.remove_group(inner).unwrap();
+    assert_eq!(scene.shape_groups(), &[None]);
+    assert!(!scene.has_groups());
+  }
+
+  #[test]
+  fn nesting_is_limited() {
+    let mut scene = Scene::new(0.5);
+    let mut parent = None;
+    for _ in 0..MAX_GROUP_DEPTH {
+      parent = Some(scene.new_group(parent, OP_UNION, 0.0).unwrap());
+    }
+    assert!(scene.new_group(parent, OP_UNION, 0.0).is_err());
+  }
+}
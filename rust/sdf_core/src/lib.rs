@@ -0,0 +1,19 @@
+//! Engine independent copy of the SDF scene that the compute shaders and the
+//! renderer evaluate, so distance queries can run (and be tested) without a GPU.
+
+pub mod grid;
+pub mod group;
+pub mod map;
+pub mod map_file;
+pub mod material;
+pub mod math;
+pub mod parity;
+pub mod query;
+pub mod scene;
+pub mod sdf;
+pub mod shape;
+
+pub use material::{Material, MaterialTable};
+pub use math::{Vec3, Vec4};
+pub use scene::{Scene, ShapeHandle};
+pub use shape::{Operation, SdfShape, ShapeFlags, ShapeKind};
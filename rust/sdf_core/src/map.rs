@@ -0,0 +1,602 @@
+use std::fmt;
+
+use crate::material::{
+  Material, MaterialTable, MAX_MATERIALS, PATTERN_CHECKER, PATTERN_NONE, PATTERN_STRIPES,
+};
+use crate::math::{Vec3, Vec4};
+use crate::scene::{
+  Scene, ALL_LAYERS, BLEND_SCENE, DEFAULT_MODIFIERS, FLAGS_SOLID, OP_INTERSECT, OP_SUBTRACT,
+  OP_UNION, SHAPE_CAPSULE, SHAPE_CONE, SHAPE_CUBE, SHAPE_CYLINDER, SHAPE_NONE, SHAPE_PLANE,
+  SHAPE_SPHERE, SHAPE_TORUS,
+};
+use crate::shape::PackedShape;
+
+/// Map file key of every shape type.
+pub const SHAPE_KEYS: [(&str, f32); 7] = [
+  ("sphere", SHAPE_SPHERE),
+  ("cube", SHAPE_CUBE),
+  ("capsule", SHAPE_CAPSULE),
+  ("torus", SHAPE_TORUS),
+  ("cylinder", SHAPE_CYLINDER),
+  ("cone", SHAPE_CONE),
+  ("plane", SHAPE_PLANE),
+];
+
+/// Map file names of every operation.
+pub const OPERATION_KEYS: [(&str, f32); 3] = [
+  ("union", OP_UNION),
+  ("subtract", OP_SUBTRACT),
+  ("intersect", OP_INTERSECT),
+];
+
+/// Map file names of every material pattern.
+pub const PATTERN_KEYS: [(&str, u32); 3] = [
+  ("none", PATTERN_NONE),
+  ("checker", PATTERN_CHECKER),
+  ("stripes", PATTERN_STRIPES),
+];
+
+/// A cube with rounding, its scale is the outer size like an unrounded cube.
+pub const ROUNDED_BOX_KEY: &str = "rounded_box";
+/// Starts a line defining a material instead of a shape.
+pub const MATERIAL_KEY: &str = "material";
+
+pub fn shape_key(shape: f32) -> Option<&'static str> {
+  return SHAPE_KEYS
+    .iter()
+    .find(|(_, kind)| *kind == shape)
+    .map(|(key, _)| *key);
+}
+
+pub fn shape_kind(key: &str) -> f32 {
+  return SHAPE_KEYS
+    .iter()
+    .find(|(name, _)| *name == key)
+    .map_or(SHAPE_NONE, |(_, kind)| *kind);
+}
+
+/// Where and why a map failed to load.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapError {
+  /// The map's path, once `in_file` named it.
+  pub file: Option<String>,
+  /// 1-based line of the offending token, `0` for errors that are not about
+  /// any one place in the map.
+  pub line: usize,
+  /// 1-based column of the offending token, counted in characters.
+  pub column: usize,
+  pub kind: MapErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapErrorKind {
+  /// The token at the location is not what the parser expected, `found` is
+  /// empty when the line ended first.
+  Expected {
+    expected: &'static str,
+    found: String,
+  },
+  /// The line is well formed but the scene could not take it, e.g. because
+  /// it is full.
+  Rejected(&'static str),
+  /// A structured map that is not valid RON or does not match `MapFile`.
+  Syntax(String),
+}
+
+impl MapError {
+  /// Names the file the map was read from in the error's location.
+  pub fn in_file(mut self, file: &str) -> Self {
+    self.file = Some(file.to_string());
+    return self;
+  }
+}
+
+impl fmt::Display for MapError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file)?;
+    }
+    if self.line > 0 {
+      write!(f, "{}:{}:", self.line, self.column)?;
+    }
+    if self.file.is_some() || self.line > 0 {
+      write!(f, " ")?;
+    }
+    match &self.kind {
+      MapErrorKind::Expected { expected, found } if found.is_empty() => {
+        return write!(f, "expected {}, found the end of the line", expected);
+      }
+      MapErrorKind::Expected { expected, found } => {
+        return write!(f, "expected {}, found `{}`", expected, found);
+      }
+      MapErrorKind::Rejected(reason) => return write!(f, "{}", reason),
+      MapErrorKind::Syntax(message) => return write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for MapError {}
+
+/// Adds every shape in a map file to `scene`, lines look like
+/// `sphere\tposition x y z\tscale x y z\tcolor r g b`, optionally followed by
+/// `\tradius r` rounding the shape, `\trotation x y z` in degrees,
+/// `\toperation subtract`, `\tblend k`, `\tlayers bits` with the shape's
+/// collision layers and `\tmaterial id`. Fields can come in any order and be
+/// separated by any whitespace, `#` starts a comment that runs to the end of
+/// the line.
+///
+/// Lines like `material 1\talbedo r g b` define the material with that id
+/// instead, optionally followed by `\troughness r`, `\temission r g b`,
+/// `\tpattern checker`, `\tfriction f` and `\tbounciness b`.
+///
+/// The whole map is parsed, and checked to fit in `scene`, before anything is
+/// added, so a map that fails to load leaves `scene` untouched.
+pub fn load_map(scene: &mut Scene, content: &str) -> Result<(), MapError> {
+  let mut entries = Vec::new();
+  for (index, text) in content.lines().enumerate() {
+    let mut tokens = LineTokens::new(index + 1, text);
+    let Some((column, key)) = tokens.take() else {
+      continue;
+    };
+    let entry = if key == MATERIAL_KEY {
+      parse_material(&mut tokens)?
+    } else {
+      parse_shape(&mut tokens, column, key)?
+    };
+    entries.push((tokens.line, column, entry));
+  }
+
+  // material ids are bounded when parsed, a full scene is the only failure
+  // left, reported at the first shape that does not fit
+  let free = scene.max_shapes() - scene.num_shapes();
+  let overflow = entries
+    .iter()
+    .filter(|(_, _, entry)| matches!(entry, MapEntry::Shape(_)))
+    .nth(free);
+  if let Some((line, column, _)) = overflow {
+    return Err(MapError {
+      file: None,
+      line: *line,
+      column: *column,
+      kind: MapErrorKind::Rejected("Cannot allocate new shape, maximum amount of shapes allocated"),
+    });
+  }
+
+  for (line, column, entry) in entries {
+    let rejected = |reason| MapError {
+      file: None,
+      line,
+      column,
+      kind: MapErrorKind::Rejected(reason),
+    };
+    match entry {
+      MapEntry::Material(id, material) => {
+        scene.materials_mut().set(id, material).map_err(rejected)?;
+      }
+      MapEntry::Shape(shape) => {
+        let handle = scene
+          .new_shape(
+            shape.position,
+            shape.rotation,
+            shape.properties,
+            shape.color,
+          )
+          .map_err(rejected)?;
+        if shape.modifiers != DEFAULT_MODIFIERS {
+          scene
+            .set_modifiers(handle, shape.modifiers)
+            .map_err(rejected)?;
+        }
+      }
+    }
+  }
+  return Ok(());
+}
+
+/// The map line `load_map` reads back into the shape at dense `index`, `None`
+/// for shapes without a key.
+pub fn format_shape(scene: &Scene, index: usize) -> Option<String> {
+  let position = scene.positions()[index];
+  let properties = scene.properties()[index];
+  let color = scene.colors()[index];
+  let modifiers = scene.modifiers()[index];
+  let rounding = modifiers.x;
+  let (key, scale) = if properties.w == SHAPE_CUBE && rounding > 0.0 {
+    let outer = properties.xyz() + Vec3::new(rounding, rounding, rounding);
+    (ROUNDED_BOX_KEY, outer)
+  } else {
+    (shape_key(properties.w)?, properties.xyz())
+  };
+
+  let mut output = format!(
+    "{}\tposition {} {} {}\tscale {} {} {}\tcolor {} {} {}",
+    key, position.x, position.y, position.z, scale.x, scale.y, scale.z, color.x, color.y, color.z
+  );
+  if rounding != 0.0 {
+    output = format!("{}\tradius {}", output, rounding);
+  }
+
+  let rotation = scene.rotations()[index].quat_to_euler();
+  // the angles only survive the quaternion round trip to about a thousandth
+  // of a degree, rounding keeps hand written angles readable
+  let [x, y, z] =
+    [rotation.x, rotation.y, rotation.z].map(|angle| (angle * 1000.0).round() / 1000.0 + 0.0);
+  if x != 0.0 || y != 0.0 || z != 0.0 {
+    output = format!("{}\trotation {} {} {}", output, x, y, z);
+  }
+
+  if modifiers.y != OP_UNION {
+    let (name, _) = OPERATION_KEYS
+      .iter()
+      .find(|(_, operation)| *operation == modifiers.y)?;
+    output = format!("{}\toperation {}", output, name);
+  }
+  if modifiers.z != BLEND_SCENE {
+    output = format!("{}\tblend {}", output, modifiers.z);
+  }
+  if modifiers.w != DEFAULT_MODIFIERS.w {
+    output = format!("{}\tlayers {}", output, modifiers.w as u32);
+  }
+  if color.w != 0.0 {
+    output = format!("{}\tmaterial {}", output, color.w as u32);
+  }
+  return Some(output);
+}
+
+/// The map line `load_map` reads back into the material with `id`.
+pub fn format_material(materials: &MaterialTable, id: u32) -> String {
+  let material = materials.get(id);
+  let default = Material::default();
+  let albedo = material.albedo;
+  let mut output = format!(
+    "{} {}\talbedo {} {} {}",
+    MATERIAL_KEY, id, albedo.x, albedo.y, albedo.z
+  );
+  if material.roughness != default.roughness {
+    output = format!("{}\troughness {}", output, material.roughness);
+  }
+  if material.emission != default.emission {
+    let emission = material.emission;
+    output = format!(
+      "{}\temission {} {} {}",
+      output, emission.x, emission.y, emission.z
+    );
+  }
+  if let Some((name, _)) = PATTERN_KEYS
+    .iter()
+    .find(|(_, pattern)| *pattern == material.pattern && *pattern != default.pattern)
+  {
+    output = format!("{}\tpattern {}", output, name);
+  }
+  if material.friction != default.friction {
+    output = format!("{}\tfriction {}", output, material.friction);
+  }
+  if material.bounciness != default.bounciness {
+    output = format!("{}\tbounciness {}", output, material.bounciness);
+  }
+  return output;
+}
+
+/// A parsed map line.
+enum MapEntry {
+  Material(u32, Material),
+  Shape(PackedShape),
+}
+
+/// The whitespace separated tokens of one map line with their columns,
+/// without the comment.
+struct LineTokens<'a> {
+  line: usize,
+  tokens: Vec<(usize, &'a str)>,
+  next: usize,
+}
+
+impl<'a> LineTokens<'a> {
+  fn new(line: usize, text: &'a str) -> Self {
+    let content = text.split('#').next().unwrap_or_default();
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (column, (byte, character)) in content.char_indices().enumerate() {
+      if character.is_whitespace() {
+        if let Some((start_column, start_byte)) = start.take() {
+          tokens.push((start_column, &content[start_byte..byte]));
+        }
+      } else if start.is_none() {
+        start = Some((column + 1, byte));
+      }
+    }
+    if let Some((start_column, start_byte)) = start {
+      tokens.push((start_column, &content[start_byte..]));
+    }
+
+    return Self {
+      line,
+      tokens,
+      next: 0,
+    };
+  }
+
+  fn error(&self, column: usize, expected: &'static str, found: &str) -> MapError {
+    return MapError {
+      file: None,
+      line: self.line,
+      column,
+      kind: MapErrorKind::Expected {
+        expected,
+        found: found.to_string(),
+      },
+    };
+  }
+
+  /// Points just past the last token.
+  fn end_error(&self, expected: &'static str) -> MapError {
+    let column = self
+      .tokens
+      .last()
+      .map_or(1, |(column, token)| column + token.chars().count());
+    return self.error(column, expected, "");
+  }
+
+  fn take(&mut self) -> Option<(usize, &'a str)> {
+    let token = self.tokens.get(self.next).copied();
+    self.next += 1;
+    return token;
+  }
+
+  fn expect(&mut self, expected: &'static str) -> Result<(usize, &'a str), MapError> {
+    return self.take().ok_or_else(|| self.end_error(expected));
+  }
+
+  fn number(&mut self) -> Result<f32, MapError> {
+    let (column, token) = self.expect("a number")?;
+    return token
+      .parse::<f32>()
+      .ok()
+      .filter(|number| number.is_finite())
+      .ok_or_else(|| self.error(column, "a number", token));
+  }
+
+  fn vector(&mut self) -> Result<Vec3, MapError> {
+    return Ok(Vec3::new(self.number()?, self.number()?, self.number()?));
+  }
+
+  fn integer(&mut self, max: u32, expected: &'static str) -> Result<u32, MapError> {
+    let (column, token) = self.expect(expected)?;
+    return token
+      .parse::<u32>()
+      .ok()
+      .filter(|integer| *integer <= max)
+      .ok_or_else(|| self.error(column, expected, token));
+  }
+
+  fn key<T: Copy>(&mut self, keys: &[(&str, T)], expected: &'static str) -> Result<T, MapError> {
+    let (column, token) = self.expect(expected)?;
+    return keys
+      .iter()
+      .find(|(name, _)| *name == token)
+      .map(|(_, value)| *value)
+      .ok_or_else(|| self.error(column, expected, token));
+  }
+
+  /// The next field name, `None` at the end of the line. Names already seen
+  /// on the line are an error.
+  fn field(&mut self, seen: &mut Vec<&'a str>) -> Result<Option<(usize, &'a str)>, MapError> {
+    let Some((column, name)) = self.take() else {
+      return Ok(None);
+    };
+    if seen.contains(&name) {
+      return Err(self.error(column, "a field not already on the line", name));
+    }
+    seen.push(name);
+    return Ok(Some((column, name)));
+  }
+}
+
+fn parse_shape(tokens: &mut LineTokens, column: usize, key: &str) -> Result<MapEntry, MapError> {
+  let rounded_box = key == ROUNDED_BOX_KEY;
+  let shape = if rounded_box {
+    SHAPE_CUBE
+  } else {
+    shape_kind(key)
+  };
+  if shape == SHAPE_NONE {
+    return Err(tokens.error(column, "a shape or material key", key));
+  }
+
+  let mut position = None;
+  let mut scale = None;
+  let mut color = None;
+  let mut material = 0;
+  let mut modifiers = DEFAULT_MODIFIERS;
+  let mut rotation = Vec4::QUAT_IDENTITY;
+  let mut seen = Vec::new();
+  while let Some((column, name)) = tokens.field(&mut seen)? {
+    match name {
+      "position" => position = Some(tokens.vector()?),
+      "scale" => scale = Some((column, tokens.vector()?)),
+      "color" => color = Some(tokens.vector()?),
+      "radius" => modifiers.x = tokens.number()?,
+      "operation" => modifiers.y = tokens.key(&OPERATION_KEYS, "an operation")?,
+      "blend" => modifiers.z = tokens.number()?,
+      "layers" => modifiers.w = tokens.integer(ALL_LAYERS, "collision layer bits")? as f32,
+      "material" => material = tokens.integer(MAX_MATERIALS as u32 - 1, "a material id")?,
+      "rotation" => rotation = Vec4::quat_from_euler(tokens.vector()?),
+      _ => return Err(tokens.error(column, "a shape field", name)),
+    }
+  }
+
+  let position = position.ok_or_else(|| tokens.end_error("a position field"))?;
+  let (scale_column, scale) = scale.ok_or_else(|| tokens.end_error("a scale field"))?;
+  let color = color.ok_or_else(|| tokens.end_error("a color field"))?;
+
+  let rounding = modifiers.x;
+  let mut properties = scale.extend(shape);
+  if rounded_box {
+    let inner = scale - Vec3::new(rounding, rounding, rounding);
+    properties = inner.max(0.0).extend(SHAPE_CUBE);
+  }
+  if shape == SHAPE_PLANE && scale.length() == 0.0 {
+    let found = format!("{} {} {}", scale.x, scale.y, scale.z);
+    return Err(tokens.error(scale_column, "a non-zero plane normal", &found));
+  }
+
+  return Ok(MapEntry::Shape(PackedShape {
+    position: position.extend(FLAGS_SOLID),
+    rotation,
+    properties,
+    color: color.extend(material as f32),
+    modifiers,
+  }));
+}
+
+fn parse_material(tokens: &mut LineTokens) -> Result<MapEntry, MapError> {
+  let id = tokens.integer(MAX_MATERIALS as u32 - 1, "a material id")?;
+  let mut material = Material::default();
+  let mut seen = Vec::new();
+  while let Some((column, name)) = tokens.field(&mut seen)? {
+    match name {
+      "albedo" => material.albedo = tokens.vector()?,
+      "roughness" => material.roughness = tokens.number()?,
+      "emission" => material.emission = tokens.vector()?,
+      "pattern" => material.pattern = tokens.key(&PATTERN_KEYS, "a pattern")?,
+      "friction" => material.friction = tokens.number()?,
+      "bounciness" => material.bounciness = tokens.number()?,
+      _ => return Err(tokens.error(column, "a material field", name)),
+    }
+  }
+  return Ok(MapEntry::Material(id, material));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn loads_shapes() {
+    let mut scene = Scene::default();
+    load_map(
+      &mut scene,
+      "sphere\tposition 0 -4.75 0\tscale 3 0 0\tcolor 1 0 0\n\
+       cube\tposition -26 -3 0\tscale 30 0.5 5\tcolor 0 1 1\n",
+    )
+    .unwrap();
+
+    assert_eq!(scene.num_shapes(), 2);
+    assert_eq!(
+      scene.positions()[0],
+      Vec4::new(0.0, -4.75, 0.0, FLAGS_SOLID)
+    );
+    assert_eq!(scene.properties()[1], Vec4::new(30.0, 0.5, 5.0, SHAPE_CUBE));
+    assert_eq!(scene.colors()[1], Vec4::new(0.0, 1.0, 1.0, 0.0));
+  }
+
+  #[test]
+  fn rejects_invalid_numbers() {
+    let mut scene = Scene::default();
+    let error = load_map(
+      &mut scene,
+      "sphere\tposition 0 0 0\tscale 3 0 0\tcolor 1 0 0\n\
+       sphere\tposition 0 x 0\tscale 3 0 0\tcolor 1 0 0",
+    )
+    .unwrap_err();
+
+    assert_eq!((error.line, error.column), (2, 19));
+    assert_eq!(
+      error.in_file("maps/test.txt").to_string(),
+      "maps/test.txt:2:19: expected a number, found `x`"
+    );
+    assert_eq!(scene.num_shapes(), 0);
+  }
+
+  #[test]
+  fn maps_that_do_not_fit_add_nothing() {
+    let mut scene = Scene::with_max_shapes(0.5, 1);
+    let error = load_map(
+      &mut scene,
+      "material 1\talbedo 1 0 0\n\
+       sphere\tposition 0 0 0\tscale 3 0 0\tcolor 1 0 0\n\
+       \tsphere\tposition 0 1 0\tscale 3 0 0\tcolor 1 0 0",
+    )
+    .unwrap_err();
+
+    assert_eq!((error.line, error.column), (3, 2));
+    assert_eq!(scene.num_shapes(), 0);
+    assert_eq!(scene.materials().len(), 1);
+  }
+
+  #[test]
+  fn tolerates_whitespace_and_comments() {
+    let mut scene = Scene::default();
+    load_map(
+      &mut scene,
+      "# the floor\n\
+       \n\
+       \x20 cube  color 0 1 1   scale 30 0.5 5\tposition -26 -3 0 # under the spawn\r\n\
+       \t\t# nothing here\n",
+    )
+    .unwrap();
+
+    assert_eq!(scene.num_shapes(), 1);
+    assert_eq!(
+      format_shape(&scene, 0).as_deref(),
+      Some("cube\tposition -26 -3 0\tscale 30 0.5 5\tcolor 0 1 1")
+    );
+  }
+
+  #[test]
+  fn every_shape_round_trips() {
+    let lines = [
+      "sphere\tposition 0 1 0\tscale 1 0 0\tcolor 1 0 0",
+      "cube\tposition 0 0 2\tscale 1 2 3\tcolor 0 1 0",
+      "capsule\tposition 1 0 0\tscale 0.5 1 0\tcolor 0 0 1",
+      "torus\tposition 0 0 0\tscale 2 0.5 0\tcolor 1 1 0",
+      "cylinder\tposition 0 0 0\tscale 1 2 0\tcolor 0 1 1\tradius 0.25",
+      "cone\tposition 0 0 0\tscale 1 1 0\tcolor 1 0 1",
+      "plane\tposition 0 -5 0\tscale 0 1 0\tcolor 1 1 1",
+      "rounded_box\tposition 0 0 0\tscale 2 1 1\tcolor 1 1 1\tradius 0.5",
+      "cube\tposition 0 0 0\tscale 2 1 1\tcolor 1 1 1\trotation 30 45 -60",
+      "capsule\tposition 0 0 0\tscale 1 1 0\tcolor 1 1 1\tradius 0.5\trotation 0 0 90",
+      "cube\tposition 0 1 0\tscale 1 2 1\tcolor 0 0 0\toperation subtract\tblend 0.25",
+      "sphere\tposition 0 0 0\tscale 8 0 0\tcolor 0 0 0\toperation intersect",
+      "sphere\tposition 0 0 0\tscale 8 0 0\tcolor 0 0 0\tblend 0",
+      "cube\tposition 0 0 0\tscale 1 1 1\tcolor 1 1 1\tlayers 6",
+      "cube\tposition 0 0 0\tscale 1 1 1\tcolor 1 1 1\tmaterial 2",
+    ];
+    let mut scene = Scene::default();
+    load_map(&mut scene, &lines.join("\n")).unwrap();
+
+    assert_eq!(scene.num_shapes(), lines.len());
+    assert_eq!(scene.properties()[7], Vec4::new(1.5, 0.5, 0.5, SHAPE_CUBE));
+    for (i, line) in lines.iter().enumerate() {
+      assert_eq!(format_shape(&scene, i).as_deref(), Some(*line));
+    }
+  }
+
+  #[test]
+  fn rejects_planes_without_a_normal() {
+    let mut scene = Scene::default();
+    let error = load_map(
+      &mut scene,
+      "plane\tposition 0 0 0\tscale 0 0 0\tcolor 1 1 1",
+    )
+    .unwrap_err();
+
+    assert_eq!((error.line, error.column), (1, 22));
+  }
+
+  #[test]
+  fn materials_round_trip() {
+    let lines = [
+      "material 1\talbedo 0.5 0.5 1\troughness 0.2\tfriction 0\tbounciness 0.5",
+      "material 2\talbedo 1 1 1\temission 1 0.5 0\tpattern checker",
+    ];
+    let mut scene = Scene::default();
+    load_map(&mut scene, &lines.join("\n")).unwrap();
+
+    assert_eq!(scene.num_shapes(), 0);
+    assert_eq!(scene.materials().get(1).friction, 0.0);
+    for (id, line) in lines.iter().enumerate() {
+      assert_eq!(format_material(scene.materials(), id as u32 + 1), *line);
+    }
+  }
+}
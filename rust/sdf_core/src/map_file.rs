@@ -0,0 +1,666 @@
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::group::MAX_GROUP_DEPTH;
+use crate::map::{self, MapError, MapErrorKind};
+use crate::material::{Material, MAX_MATERIALS};
+use crate::math::Vec3;
+use crate::scene::{Scene, ShapeHandle, BLEND_SCENE};
+use crate::shape::{Operation, SdfShape};
+
+/// Version `MapFile::to_ron` writes. Older maps are upgraded when they are
+/// parsed, tab separated maps count as version `0`:
+/// - `1` the first structured maps
+/// - `2` adds `groups`
+pub const MAP_VERSION: u32 = 2;
+
+/// Extension of structured maps, every other map is read as a tab separated
+/// one.
+pub const MAP_FILE_EXTENSION: &str = ".ron";
+
+/// Extension of tab separated maps, which `convert_map` turns into structured
+/// ones.
+pub const TAB_MAP_EXTENSION: &str = ".txt";
+
+/// A map in the structured RON format, e.g.
+/// `(version: 2, shapes: [(kind: Sphere(radius: 3.0), position: (0.0, -4.75, 0.0))])`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MapFile {
+  pub version: u32,
+  /// Indexed by the shapes' material ids, empty for maps with only the
+  /// default material.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub materials: Vec<Material>,
+  #[serde(default)]
+  pub shapes: Vec<SdfShape>,
+  /// Every group comes after its parent.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub groups: Vec<MapGroup>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub entities: Vec<Entity>,
+}
+
+/// A scene group, see `Group`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MapGroup {
+  /// Index of the parent in `MapFile::groups`, `None` for groups directly in
+  /// the scene.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub parent: Option<usize>,
+  #[serde(default)]
+  pub operation: Operation,
+  /// `None` uses the scene's `blend_factor`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub blend_radius: Option<f32>,
+  /// Indices of the group's own shapes in `MapFile::shapes`.
+  #[serde(default)]
+  pub shapes: Vec<usize>,
+}
+
+/// What a map places besides its shapes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Entity {
+  PlayerSpawn { position: Vec3 },
+}
+
+/// What a map added to a scene, in the map's order, for `MapFile::reload`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppliedMap {
+  pub shapes: Vec<ShapeHandle>,
+  /// Group addresses.
+  pub groups: Vec<usize>,
+}
+
+/// Only the version, read first to pick how the rest of the map is parsed.
+#[derive(Deserialize)]
+struct VersionHeader {
+  version: u32,
+}
+
+impl Default for MapFile {
+  fn default() -> Self {
+    return Self {
+      version: MAP_VERSION,
+      materials: Vec::new(),
+      shapes: Vec::new(),
+      groups: Vec::new(),
+      entities: Vec::new(),
+    };
+  }
+}
+
+impl MapFile {
+  /// Every shape, group and material in `scene`, without entities.
+  pub fn from_scene(scene: &Scene) -> Self {
+    return Self::from_scene_without(scene, &[]);
+  }
+
+  /// `from_scene` leaving out the shapes in `excluded`, e.g. ones the game
+  /// spawned that are not part of the level.
+  pub fn from_scene_without(scene: &Scene, excluded: &[ShapeHandle]) -> Self {
+    let materials = scene.materials().materials();
+    let only_default = materials.len() == 1 && materials[0] == Material::default();
+
+    // parents have to come first, so groups are written shallowest first
+    let depth = |mut address: usize| {
+      let mut depth = 0;
+      while let Some(parent) = scene.group(address).and_then(|group| group.parent) {
+        address = parent;
+        depth += 1;
+      }
+      return depth;
+    };
+    let mut addresses: Vec<usize> = scene.groups().map(|(address, _)| address).collect();
+    addresses.sort_by_key(|address| depth(*address));
+    let index_of = |address: usize| addresses.iter().position(|other| *other == address);
+
+    let mut groups: Vec<MapGroup> = addresses
+      .iter()
+      .map(|address| {
+        let group = scene.group(*address).unwrap();
+        MapGroup {
+          parent: group.parent.and_then(index_of),
+          operation: Operation::unpack(group.operation).unwrap_or_default(),
+          blend_radius: (group.blend_radius >= 0.0).then_some(group.blend_radius),
+          shapes: Vec::new(),
+        }
+      })
+      .collect();
+
+    let mut shapes = Vec::new();
+    for (index, handle) in scene.handles().iter().enumerate() {
+      if excluded.contains(handle) {
+        continue;
+      }
+      let Some(shape) = scene.shape(*handle) else {
+        continue;
+      };
+      if let Some(group) = scene.shape_groups()[index].and_then(index_of) {
+        groups[group].shapes.push(shapes.len());
+      }
+      shapes.push(shape);
+    }
+
+    return Self {
+      materials: if only_default {
+        Vec::new()
+      } else {
+        materials.to_vec()
+      },
+      shapes,
+      groups,
+      ..Self::default()
+    };
+  }
+
+  /// Adds the map's shapes and groups to `scene` and replaces its materials
+  /// with the map's.
+  pub fn apply(&self, scene: &mut Scene) -> Result<AppliedMap, MapError> {
+    return self.reload(scene, &AppliedMap::default());
+  }
+
+  /// Clears `scene` and applies the map to it. A map that would not fit even
+  /// the empty scene is rejected, like `reload` does, before `scene` is
+  /// cleared.
+  pub fn replace(&self, scene: &mut Scene) -> Result<AppliedMap, MapError> {
+    let empty = Scene::with_max_shapes(scene.blend_factor, scene.max_shapes());
+    self.check(&empty, &AppliedMap::default())?;
+    scene.clear();
+    return self.apply(scene);
+  }
+
+  /// Replaces what `previous` added to `scene` with this map. Shapes are
+  /// updated in place, in order, so the map's shapes keep their handles and
+  /// shapes added outside the map are left alone. A map that does not fit in
+  /// `scene` is rejected before anything changes.
+  pub fn reload(&self, scene: &mut Scene, previous: &AppliedMap) -> Result<AppliedMap, MapError> {
+    self.check(scene, previous)?;
+
+    let mut materials = self.materials.clone();
+    if materials.is_empty() {
+      materials.push(Material::default());
+    }
+    if scene.materials().materials() != materials.as_slice() {
+      scene.materials_mut().clear();
+      for (id, material) in materials.iter().enumerate() {
+        scene
+          .materials_mut()
+          .set(id as u32, *material)
+          .map_err(rejected)?;
+      }
+    }
+
+    // children were created after their parents, so they are removed first
+    for address in previous.groups.iter().rev() {
+      if scene.group(*address).is_some() {
+        scene.remove_group(*address).map_err(rejected)?;
+      }
+    }
+
+    // shapes of the previous map that were since removed are not reused
+    let previous_shapes: Vec<ShapeHandle> = previous
+      .shapes
+      .iter()
+      .copied()
+      .filter(|handle| scene.index_of(*handle).is_some())
+      .collect();
+    for handle in previous_shapes.iter().skip(self.shapes.len()) {
+      scene.remove_shape(*handle).map_err(rejected)?;
+    }
+
+    let mut applied = AppliedMap::default();
+    for (index, shape) in self.shapes.iter().enumerate() {
+      let handle = match previous_shapes.get(index) {
+        Some(handle) => {
+          // unchanged shapes are not rewritten
+          if scene.shape(*handle).as_ref() != Some(shape) {
+            scene.set_shape(*handle, shape).map_err(rejected)?;
+          }
+          *handle
+        }
+        None => scene.insert_shape(shape).map_err(rejected)?,
+      };
+      applied.shapes.push(handle);
+    }
+
+    for group in &self.groups {
+      let parent = match group.parent {
+        Some(parent) => Some(
+          *applied
+            .groups
+            .get(parent)
+            .ok_or(rejected("Map group comes before its parent"))?,
+        ),
+        None => None,
+      };
+      let blend_radius = group.blend_radius.unwrap_or(BLEND_SCENE);
+      let address = scene
+        .new_group(parent, group.operation.pack(), blend_radius)
+        .map_err(rejected)?;
+      for shape in &group.shapes {
+        let handle = applied
+          .shapes
+          .get(*shape)
+          .ok_or(rejected("Map group has a shape that does not exist"))?;
+        scene
+          .set_shape_group(*handle, Some(address))
+          .map_err(rejected)?;
+      }
+      applied.groups.push(address);
+    }
+    return Ok(applied);
+  }
+
+  /// Everything `reload` could fail on once it starts changing `scene`.
+  fn check(&self, scene: &Scene, previous: &AppliedMap) -> Result<(), MapError> {
+    if self.materials.len() > MAX_MATERIALS {
+      return Err(rejected(
+        "Material id is past the maximum number of materials",
+      ));
+    }
+
+    let mut depths: Vec<usize> = Vec::with_capacity(self.groups.len());
+    for (index, group) in self.groups.iter().enumerate() {
+      let depth = match group.parent {
+        Some(parent) if parent < index => depths[parent] + 1,
+        Some(_) => return Err(rejected("Map group comes before its parent")),
+        None => 1,
+      };
+      if depth > MAX_GROUP_DEPTH {
+        return Err(rejected("Map groups are nested too deep"));
+      }
+      if group.shapes.iter().any(|shape| *shape >= self.shapes.len()) {
+        return Err(rejected("Map group has a shape that does not exist"));
+      }
+      depths.push(depth);
+    }
+
+    // what the previous map still holds is replaced rather than added to
+    let previous_shapes = previous
+      .shapes
+      .iter()
+      .filter(|handle| scene.index_of(**handle).is_some())
+      .count();
+    if scene.num_shapes() - previous_shapes + self.shapes.len() > scene.max_shapes() {
+      return Err(rejected("Map has more shapes than the scene can hold"));
+    }
+    let previous_groups = previous
+      .groups
+      .iter()
+      .filter(|address| scene.group(**address).is_some())
+      .count();
+    if scene.groups().count() - previous_groups + self.groups.len() > scene.max_shapes() {
+      return Err(rejected("Map has more groups than the scene can hold"));
+    }
+    return Ok(());
+  }
+
+  /// The first player spawn, if the map has one.
+  pub fn player_spawn(&self) -> Option<Vec3> {
+    return player_spawn(&self.entities);
+  }
+
+  pub fn to_ron(&self) -> String {
+    let config = PrettyConfig::new().indentor("  ").struct_names(false);
+    // none of the map's types can fail to serialize
+    return ron::ser::to_string_pretty(self, config).unwrap();
+  }
+}
+
+/// The first player spawn among `entities`.
+// stays a `find_map` for the entities that are not spawns
+#[allow(clippy::unnecessary_find_map)]
+pub fn player_spawn(entities: &[Entity]) -> Option<Vec3> {
+  return entities.iter().find_map(|entity| match entity {
+    Entity::PlayerSpawn { position } => Some(*position),
+  });
+}
+
+/// Reads a structured map, upgrading maps saved with an older version.
+pub fn parse(content: &str) -> Result<MapFile, MapError> {
+  let header: VersionHeader = ron::from_str(content).map_err(syntax_error)?;
+  match header.version {
+    MAP_VERSION => return ron::from_str(content).map_err(syntax_error),
+    // upgrades from older versions go here, parsing the old layout and
+    // converting it to the current one
+    1 => {
+      // version 2 only added groups, which default to none
+      let mut map: MapFile = ron::from_str(content).map_err(syntax_error)?;
+      map.version = MAP_VERSION;
+      return Ok(map);
+    }
+    version if version > MAP_VERSION => {
+      return Err(rejected("Map was saved by a newer version of the game"));
+    }
+    _ => return Err(rejected("Map has an unknown version")),
+  }
+}
+
+/// Converts a tab separated map into a structured one, see `map::load_map`
+/// for the format.
+pub fn import_tab_map(content: &str) -> Result<MapFile, MapError> {
+  let num_lines = content.lines().count().max(1);
+  let mut scene = Scene::with_max_shapes(0.0, num_lines);
+  map::load_map(&mut scene, content)?;
+  return Ok(MapFile::from_scene(&scene));
+}
+
+/// `scene` with `entities` as a structured map, which `load` reads back into
+/// the same scene minus the `excluded` shapes.
+pub fn save(scene: &Scene, entities: &[Entity], excluded: &[ShapeHandle]) -> String {
+  let map = MapFile {
+    entities: entities.to_vec(),
+    ..MapFile::from_scene_without(scene, excluded)
+  };
+  return map.to_ron();
+}
+
+/// Whether `path` names a map in either format, for picking maps out of a
+/// directory.
+pub fn is_map_path(path: &str) -> bool {
+  return path.ends_with(MAP_FILE_EXTENSION) || path.ends_with(TAB_MAP_EXTENSION);
+}
+
+/// Reads the map at `path` with `content` without touching a scene, picking
+/// the format from the extension. Errors name `path`.
+pub fn read(path: &str, content: &str) -> Result<MapFile, MapError> {
+  let map = if path.ends_with(MAP_FILE_EXTENSION) {
+    parse(content)
+  } else {
+    import_tab_map(content)
+  };
+  return map.map_err(|e| e.in_file(path));
+}
+
+/// Adds the map at `path` with `content` to `scene`, see `read`.
+pub fn load(scene: &mut Scene, path: &str, content: &str) -> Result<MapFile, MapError> {
+  let map = read(path, content)?;
+  map.apply(scene).map_err(|e| e.in_file(path))?;
+  return Ok(map);
+}
+
+fn syntax_error(error: ron::error::SpannedError) -> MapError {
+  return MapError {
+    file: None,
+    line: error.span.start.line,
+    column: error.span.start.col,
+    kind: MapErrorKind::Syntax(error.code.to_string()),
+  };
+}
+
+fn rejected(reason: &'static str) -> MapError {
+  return MapError {
+    file: None,
+    line: 0,
+    column: 0,
+    kind: MapErrorKind::Rejected(reason),
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::Vec4;
+  use crate::scene::{OP_SUBTRACT, OP_UNION};
+  use crate::shape::{ShapeFlags, ShapeKind};
+
+  #[test]
+  fn tab_maps_import_into_the_same_scene() {
+    let content = "material 1\talbedo 0.5 0.5 1\tfriction 0\n\
+                   sphere\tposition 0 -4.75 0\tscale 3 0 0\tcolor 1 0 0\tmaterial 1\n\
+                   cube\tposition 0 0 0\tscale 1 2 1\tcolor 0 0 0\toperation subtract\tblend 0.25";
+    let mut expected = Scene::default();
+    map::load_map(&mut expected, content).unwrap();
+
+    let imported = import_tab_map(content).unwrap();
+    let mut scene = Scene::default();
+    parse(&imported.to_ron())
+      .unwrap()
+      .apply(&mut scene)
+      .unwrap();
+
+    assert_eq!(imported.version, MAP_VERSION);
+    assert_eq!(scene.positions(), expected.positions());
+    assert_eq!(scene.properties(), expected.properties());
+    assert_eq!(scene.colors(), expected.colors());
+    assert_eq!(scene.modifiers(), expected.modifiers());
+    assert_eq!(
+      scene.materials().materials(),
+      expected.materials().materials()
+    );
+  }
+
+  #[test]
+  fn fields_left_out_keep_their_defaults() {
+    let map = parse(
+      "(
+        version: 2,
+        shapes: [
+          (kind: Sphere(radius: 1.0), position: (0.0, 1.0, 0.0)),
+          (
+            kind: Cube(half_extents: (1.0, 1.0, 1.0)),
+            position: (0.0, 0.0, 0.0),
+            flags: \"RENDER\",
+            operation: Subtract,
+          ),
+        ],
+        entities: [PlayerSpawn(position: (0.0, 2.0, 0.0))],
+      )",
+    )
+    .unwrap();
+
+    assert_eq!(
+      map.shapes[0],
+      SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::new(0.0, 1.0, 0.0))
+    );
+    assert_eq!(map.shapes[1].flags, ShapeFlags::RENDER);
+    assert_eq!(map.shapes[1].operation, Operation::Subtract);
+    assert_eq!(map.player_spawn(), Some(Vec3::new(0.0, 2.0, 0.0)));
+  }
+
+  #[test]
+  fn saved_scenes_load_unchanged() {
+    let mut scene = Scene::new(0.5);
+    scene
+      .materials_mut()
+      .set(
+        2,
+        Material {
+          roughness: 0.25,
+          ..Material::default()
+        },
+      )
+      .unwrap();
+    let shapes = [
+      SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::ZERO).with_material(2),
+      SdfShape::new(
+        ShapeKind::Cube {
+          half_extents: Vec3::new(1.0, 2.0, 3.0),
+        },
+        Vec3::new(1.0, 0.0, 0.0),
+      )
+      .with_rotation(Vec4::quat_from_euler(Vec3::new(30.0, 45.0, 60.0)))
+      .with_rounding(0.25)
+      .with_collision_layers(6),
+      // neither collides nor renders
+      SdfShape::new(ShapeKind::Sphere { radius: 2.0 }, Vec3::ZERO).with_flags(ShapeFlags::TRIGGER),
+      SdfShape::new(ShapeKind::Sphere { radius: 0.5 }, Vec3::ZERO).with_flags(ShapeFlags::RENDER),
+      SdfShape::new(ShapeKind::Sphere { radius: 0.5 }, Vec3::ZERO)
+        .with_flags(ShapeFlags::COLLIDE | ShapeFlags::PLAYER_ONLY)
+        .with_operation(Operation::Subtract, Some(0.0)),
+    ];
+    let handles: Vec<_> = shapes
+      .iter()
+      .map(|shape| scene.insert_shape(shape).unwrap())
+      .collect();
+    let outer = scene.new_group(None, OP_SUBTRACT, BLEND_SCENE).unwrap();
+    let inner = scene.new_group(Some(outer), OP_UNION, 0.1).unwrap();
+    scene.set_shape_group(handles[1], Some(outer)).unwrap();
+    scene.set_shape_group(handles[3], Some(inner)).unwrap();
+    let entities = [Entity::PlayerSpawn {
+      position: Vec3::new(0.0, 2.0, 0.0),
+    }];
+
+    let mut loaded = Scene::new(0.5);
+    // spawned by the game rather than part of the level
+    let grenade = scene
+      .insert_shape(&SdfShape::new(
+        ShapeKind::Sphere { radius: 0.2 },
+        Vec3::ZERO,
+      ))
+      .unwrap();
+    let map = load(
+      &mut loaded,
+      "saved.ron",
+      &save(&scene, &entities, &[grenade]),
+    )
+    .unwrap();
+    scene.remove_shape(grenade).unwrap();
+
+    assert_eq!(map.entities, entities);
+    assert_eq!(loaded.positions(), scene.positions());
+    assert_eq!(loaded.rotations(), scene.rotations());
+    assert_eq!(loaded.properties(), scene.properties());
+    assert_eq!(loaded.colors(), scene.colors());
+    assert_eq!(loaded.modifiers(), scene.modifiers());
+    assert_eq!(loaded.shape_groups(), scene.shape_groups());
+    assert!(loaded.groups().eq(scene.groups()));
+    assert_eq!(
+      loaded.materials().materials(),
+      scene.materials().materials()
+    );
+  }
+
+  #[test]
+  fn reloads_keep_the_handles_of_unchanged_and_dynamic_shapes() {
+    let sphere = |x: f32| SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::new(x, 0.0, 0.0));
+    let mut map = MapFile {
+      shapes: vec![sphere(0.0), sphere(1.0), sphere(2.0)],
+      groups: vec![MapGroup {
+        parent: None,
+        operation: Operation::Union,
+        blend_radius: Some(0.5),
+        shapes: vec![2],
+      }],
+      ..MapFile::default()
+    };
+    let mut scene = Scene::new(0.5);
+    let applied = map.apply(&mut scene).unwrap();
+    let grenade = scene.insert_shape(&sphere(10.0)).unwrap();
+
+    // nothing changed on disk
+    let unchanged = map.reload(&mut scene, &applied).unwrap();
+    assert_eq!(unchanged.shapes, applied.shapes);
+    assert_eq!(scene.num_shapes(), 4);
+
+    map.shapes[1] = sphere(5.0);
+    map.shapes.pop();
+    map.groups[0].shapes = vec![1];
+    let reloaded = map.reload(&mut scene, &applied).unwrap();
+
+    assert_eq!(reloaded.shapes, applied.shapes[..2]);
+    assert_eq!(scene.shape(reloaded.shapes[1]), Some(sphere(5.0)));
+    assert_eq!(scene.index_of(applied.shapes[2]), None);
+    assert_eq!(scene.shape(grenade), Some(sphere(10.0)));
+    assert_eq!(scene.num_shapes(), 3);
+    assert_eq!(scene.groups().count(), 1);
+    let grouped = scene.index_of(reloaded.shapes[1]).unwrap();
+    assert_eq!(scene.shape_groups()[grouped], Some(reloaded.groups[0]));
+
+    map.shapes.push(sphere(3.0));
+    let reloaded = map.reload(&mut scene, &reloaded).unwrap();
+    assert_eq!(reloaded.shapes.len(), 2 + 1);
+    assert_eq!(scene.shape(reloaded.shapes[2]), Some(sphere(3.0)));
+    assert_eq!(scene.shape(grenade), Some(sphere(10.0)));
+  }
+
+  #[test]
+  fn failed_reloads_leave_the_scene_unchanged() {
+    let sphere = |x: f32| SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::new(x, 0.0, 0.0));
+    let mut map = MapFile {
+      shapes: vec![sphere(0.0), sphere(1.0)],
+      ..MapFile::default()
+    };
+    let mut scene = Scene::new(0.5);
+    let applied = map.apply(&mut scene).unwrap();
+    let positions = scene.positions().to_vec();
+
+    // saved halfway through an edit
+    map.shapes.push(sphere(2.0));
+    map.groups.push(MapGroup {
+      parent: None,
+      operation: Operation::Union,
+      blend_radius: None,
+      shapes: vec![7],
+    });
+    assert!(map.reload(&mut scene, &applied).is_err());
+    assert_eq!(scene.positions(), positions);
+    assert!(!scene.has_groups());
+
+    map.groups[0].shapes = vec![2];
+    let reloaded = map.reload(&mut scene, &applied).unwrap();
+    assert_eq!(scene.num_shapes(), 3);
+    assert_eq!(reloaded.shapes[..2], applied.shapes);
+
+    let mut full = Scene::with_max_shapes(0.5, 2);
+    assert!(map.apply(&mut full).is_err());
+    assert_eq!(full.num_shapes(), 0);
+  }
+
+  #[test]
+  fn replacing_with_a_map_that_does_not_fit_keeps_the_scene() {
+    let sphere = |x: f32| SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::new(x, 0.0, 0.0));
+    let mut scene = Scene::with_max_shapes(0.5, 2);
+    let kept = scene.insert_shape(&sphere(0.0)).unwrap();
+
+    let too_big = MapFile {
+      shapes: vec![sphere(1.0), sphere(2.0), sphere(3.0)],
+      ..MapFile::default()
+    };
+    assert!(too_big.replace(&mut scene).is_err());
+    assert_eq!(scene.shape(kept), Some(sphere(0.0)));
+
+    // fits once the scene is cleared, although not next to what is there now
+    let fits = MapFile {
+      shapes: vec![sphere(1.0), sphere(2.0)],
+      ..MapFile::default()
+    };
+    let applied = fits.replace(&mut scene).unwrap();
+    assert_eq!(scene.num_shapes(), 2);
+    assert_eq!(scene.index_of(kept), None);
+    assert_eq!(scene.shape(applied.shapes[1]), Some(sphere(2.0)));
+  }
+
+  #[test]
+  fn version_one_maps_are_upgraded() {
+    let map = parse("(version: 1, shapes: [])").unwrap();
+    assert_eq!(map.version, MAP_VERSION);
+  }
+
+  #[test]
+  fn map_paths_are_recognised_in_either_format() {
+    assert!(is_map_path("user://maps/arena.ron"));
+    assert!(is_map_path("res://old_map.txt"));
+    assert!(!is_map_path("user://maps/arena.ron.import"));
+    assert!(!is_map_path("user://maps/notes"));
+  }
+
+  #[test]
+  fn newer_versions_are_rejected() {
+    let error = parse("(version: 3, shapes: [])").unwrap_err();
+    assert_eq!(
+      error.kind,
+      MapErrorKind::Rejected("Map was saved by a newer version of the game")
+    );
+  }
+
+  #[test]
+  fn syntax_errors_have_a_location() {
+    let error = parse("(\n  version: 1,\n  shapes: [(kind: Sphere(radius: 1.0))],\n)").unwrap_err();
+
+    assert_eq!(error.line, 3);
+    assert!(matches!(error.kind, MapErrorKind::Syntax(_)));
+  }
+}
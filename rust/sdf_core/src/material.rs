@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::{Vec3, Vec4};
+
+/// Most materials a `MaterialTable` holds, the width of the renderer's
+/// material texture.
+pub const MAX_MATERIALS: usize = 256;
+
+pub const PATTERN_NONE: u32 = 0;
+pub const PATTERN_CHECKER: u32 = 1;
+pub const PATTERN_STRIPES: u32 = 2;
+
+/// How a surface looks and how it responds to what hits it. Shapes refer to
+/// a material by its id in the scene's `MaterialTable`. Map files can leave
+/// out any field that keeps its default.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Material {
+  /// Multiplied with the shape's color.
+  pub albedo: Vec3,
+  /// `0.0` gives a sharp highlight, `1.0` none at all.
+  pub roughness: f32,
+  /// Added to the shaded color, lit or not.
+  pub emission: Vec3,
+  /// One of the `PATTERN_*` constants, modulating the albedo.
+  pub pattern: u32,
+  /// How much of the velocity along the surface contacts take away, `0.0`
+  /// slides like ice.
+  pub friction: f32,
+  /// Share of the velocity into the surface that is reflected back.
+  pub bounciness: f32,
+}
+
+impl Default for Material {
+  fn default() -> Self {
+    return Self {
+      albedo: Vec3::new(1.0, 1.0, 1.0),
+      roughness: 1.0,
+      emission: Vec3::ZERO,
+      pattern: PATTERN_NONE,
+      friction: 1.0,
+      bounciness: 0.0,
+    };
+  }
+}
+
+impl Material {
+  /// The material's column of the renderer's material texture:
+  /// `(albedo.rgb, roughness)`, `(emission.rgb, pattern)` and
+  /// `(friction, bounciness, unused, unused)`.
+  pub fn pack(&self) -> [Vec4; 3] {
+    return [
+      self.albedo.extend(self.roughness),
+      self.emission.extend(self.pattern as f32),
+      Vec4::new(self.friction, self.bounciness, 0.0, 0.0),
+    ];
+  }
+}
+
+/// The materials of a scene, indexed by the material id in the shapes'
+/// `colors.w`. Material `0` always exists and is what unknown ids use, both
+/// here and in the renderer, so a shape looks and behaves the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialTable {
+  materials: Vec<Material>,
+  revision: u64,
+}
+
+impl Default for MaterialTable {
+  fn default() -> Self {
+    return Self {
+      materials: vec![Material::default()],
+      revision: 0,
+    };
+  }
+}
+
+impl MaterialTable {
+  pub fn new() -> Self {
+    return Self::default();
+  }
+
+  /// Changes every time a material does, so uploads can be skipped while it
+  /// stays the same.
+  pub fn revision(&self) -> u64 {
+    return self.revision;
+  }
+
+  pub fn materials(&self) -> &[Material] {
+    return &self.materials;
+  }
+
+  pub fn len(&self) -> usize {
+    return self.materials.len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.materials.is_empty();
+  }
+
+  /// The material with `id`, falling back to material `0`.
+  pub fn get(&self, id: u32) -> Material {
+    return *self
+      .materials
+      .get(id as usize)
+      .unwrap_or(&self.materials[0]);
+  }
+
+  /// Replaces the material with `id`, filling any ids below it that were
+  /// never set with default materials.
+  pub fn set(&mut self, id: u32, material: Material) -> Result<(), &'static str> {
+    let index = id as usize;
+    if index >= MAX_MATERIALS {
+      return Err("Material id is past the maximum number of materials");
+    }
+
+    if index >= self.materials.len() {
+      self.materials.resize(index + 1, Material::default());
+    }
+    self.materials[index] = material;
+    self.revision += 1;
+    return Ok(());
+  }
+
+  /// Adds a material after the last one and returns its id.
+  pub fn push(&mut self, material: Material) -> Result<u32, &'static str> {
+    let id = self.materials.len() as u32;
+    self.set(id, material)?;
+    return Ok(id);
+  }
+
+  /// Back to only the default material.
+  pub fn clear(&mut self) {
+    self.materials.clear();
+    self.materials.push(Material::default());
+    self.revision += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unknown_ids_use_the_first_material() {
+    let mut table = MaterialTable::new();
+    let ice = Material {
+      friction: 0.0,
+      ..Material::default()
+    };
+    table.set(2, ice).unwrap();
+
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.get(1), Material::default());
+    assert_eq!(table.get(2), ice);
+    assert_eq!(table.get(7), table.get(0));
+    assert!(table.set(MAX_MATERIALS as u32, ice).is_err());
+  }
+}
@@ -0,0 +1,275 @@
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
+
+use serde::{Deserialize, Serialize};
+
+/// Map files store vectors as plain tuples, `(x, y, z)`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(from = "[f32; 3]", into = "[f32; 3]")]
+pub struct Vec3 {
+  pub x: f32,
+  pub y: f32,
+  pub z: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(from = "[f32; 4]", into = "[f32; 4]")]
+pub struct Vec4 {
+  pub x: f32,
+  pub y: f32,
+  pub z: f32,
+  pub w: f32,
+}
+
+impl From<[f32; 3]> for Vec3 {
+  fn from([x, y, z]: [f32; 3]) -> Self {
+    return Self::new(x, y, z);
+  }
+}
+
+impl From<Vec3> for [f32; 3] {
+  fn from(vector: Vec3) -> Self {
+    return [vector.x, vector.y, vector.z];
+  }
+}
+
+impl From<[f32; 4]> for Vec4 {
+  fn from([x, y, z, w]: [f32; 4]) -> Self {
+    return Self::new(x, y, z, w);
+  }
+}
+
+impl From<Vec4> for [f32; 4] {
+  fn from(vector: Vec4) -> Self {
+    return [vector.x, vector.y, vector.z, vector.w];
+  }
+}
+
+impl Vec3 {
+  pub const ZERO: Vec3 = Vec3::new(0.0, 0.0, 0.0);
+
+  pub const fn new(x: f32, y: f32, z: f32) -> Self {
+    return Self { x, y, z };
+  }
+
+  pub fn dot(self, other: Vec3) -> f32 {
+    return self.x * other.x + self.y * other.y + self.z * other.z;
+  }
+
+  pub fn length(self) -> f32 {
+    return self.dot(self).sqrt();
+  }
+
+  pub fn normalized(self) -> Vec3 {
+    let length = self.length();
+    if length == 0.0 {
+      return Vec3::ZERO;
+    }
+    return self / length;
+  }
+
+  pub fn abs(self) -> Vec3 {
+    return Vec3::new(self.x.abs(), self.y.abs(), self.z.abs());
+  }
+
+  pub fn max(self, value: f32) -> Vec3 {
+    return Vec3::new(self.x.max(value), self.y.max(value), self.z.max(value));
+  }
+
+  pub fn component_min(self, other: Vec3) -> Vec3 {
+    return Vec3::new(
+      self.x.min(other.x),
+      self.y.min(other.y),
+      self.z.min(other.z),
+    );
+  }
+
+  pub fn component_max(self, other: Vec3) -> Vec3 {
+    return Vec3::new(
+      self.x.max(other.x),
+      self.y.max(other.y),
+      self.z.max(other.z),
+    );
+  }
+
+  pub fn is_finite(self) -> bool {
+    return self.x.is_finite() && self.y.is_finite() && self.z.is_finite();
+  }
+
+  pub fn max_element(self) -> f32 {
+    return self.x.max(self.y.max(self.z));
+  }
+
+  pub fn cross(self, other: Vec3) -> Vec3 {
+    return Vec3::new(
+      self.y * other.z - self.z * other.y,
+      self.z * other.x - self.x * other.z,
+      self.x * other.y - self.y * other.x,
+    );
+  }
+
+  pub fn extend(self, w: f32) -> Vec4 {
+    return Vec4::new(self.x, self.y, self.z, w);
+  }
+}
+
+impl Vec4 {
+  pub const ZERO: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.0);
+
+  pub const fn new(x: f32, y: f32, z: f32, w: f32) -> Self {
+    return Self { x, y, z, w };
+  }
+
+  pub fn xyz(self) -> Vec3 {
+    return Vec3::new(self.x, self.y, self.z);
+  }
+}
+
+/// Rotations are stored as `Vec4` quaternions, `(x, y, z, w)` like godot's
+/// `Quaternion`.
+impl Vec4 {
+  pub const QUAT_IDENTITY: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
+
+  /// Euler angles in degrees applied in godot's default YXZ order.
+  pub fn quat_from_euler(degrees: Vec3) -> Vec4 {
+    let axis = |x: f32, y: f32, z: f32, degrees: f32| {
+      let (sin, cos) = (degrees.to_radians() * 0.5).sin_cos();
+      return Vec4::new(x * sin, y * sin, z * sin, cos);
+    };
+    return axis(0.0, 1.0, 0.0, degrees.y)
+      .quat_mul(axis(1.0, 0.0, 0.0, degrees.x))
+      .quat_mul(axis(0.0, 0.0, 1.0, degrees.z));
+  }
+
+  /// Inverse of `quat_from_euler` for unit quaternions.
+  pub fn quat_to_euler(self) -> Vec3 {
+    let Vec4 { x, y, z, w } = self;
+    let m12 = 2.0 * (y * z - x * w);
+    let (pitch, yaw, roll) = if m12.abs() < 1.0 - 1e-6 {
+      let m02 = 2.0 * (x * z + y * w);
+      let m22 = 1.0 - 2.0 * (x * x + y * y);
+      let m10 = 2.0 * (x * y + z * w);
+      let m11 = 1.0 - 2.0 * (x * x + z * z);
+      ((-m12).asin(), m02.atan2(m22), m10.atan2(m11))
+    } else {
+      // looking straight up or down, yaw and roll rotate about the same axis
+      let m01 = 2.0 * (x * y - z * w);
+      let m00 = 1.0 - 2.0 * (y * y + z * z);
+      let pitch = -m12.signum() * std::f32::consts::FRAC_PI_2;
+      (pitch, -m12.signum() * m01.atan2(m00), 0.0)
+    };
+    return Vec3::new(pitch.to_degrees(), yaw.to_degrees(), roll.to_degrees());
+  }
+
+  pub fn quat_mul(self, other: Vec4) -> Vec4 {
+    let (a, b) = (self.xyz(), other.xyz());
+    return (b * self.w + a * other.w + a.cross(b)).extend(self.w * other.w - a.dot(b));
+  }
+
+  pub fn quat_inverse(self) -> Vec4 {
+    return Vec4::new(-self.x, -self.y, -self.z, self.w);
+  }
+
+  /// Rotates `vector` by this unit quaternion.
+  pub fn quat_rotate(self, vector: Vec3) -> Vec3 {
+    let t = self.xyz().cross(vector) * 2.0;
+    return vector + t * self.w + self.xyz().cross(t);
+  }
+}
+
+impl Add for Vec3 {
+  type Output = Vec3;
+
+  fn add(self, other: Vec3) -> Vec3 {
+    return Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z);
+  }
+}
+
+impl AddAssign for Vec3 {
+  fn add_assign(&mut self, other: Vec3) {
+    *self = *self + other;
+  }
+}
+
+impl Sub for Vec3 {
+  type Output = Vec3;
+
+  fn sub(self, other: Vec3) -> Vec3 {
+    return Vec3::new(self.x - other.x, self.y - other.y, self.z - other.z);
+  }
+}
+
+impl Mul<f32> for Vec3 {
+  type Output = Vec3;
+
+  fn mul(self, scale: f32) -> Vec3 {
+    return Vec3::new(self.x * scale, self.y * scale, self.z * scale);
+  }
+}
+
+impl Div<f32> for Vec3 {
+  type Output = Vec3;
+
+  fn div(self, scale: f32) -> Vec3 {
+    return Vec3::new(self.x / scale, self.y / scale, self.z / scale);
+  }
+}
+
+impl Neg for Vec3 {
+  type Output = Vec3;
+
+  fn neg(self) -> Vec3 {
+    return Vec3::new(-self.x, -self.y, -self.z);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(a: Vec3, b: Vec3) {
+    assert!((a - b).length() < 1e-3, "{:?} != {:?}", a, b);
+  }
+
+  #[test]
+  fn euler_angles_follow_godot_order() {
+    // yaw first: +90 around y takes +x to -z
+    let yaw = Vec4::quat_from_euler(Vec3::new(0.0, 90.0, 0.0));
+    assert_close(
+      yaw.quat_rotate(Vec3::new(1.0, 0.0, 0.0)),
+      Vec3::new(0.0, 0.0, -1.0),
+    );
+
+    // roll is applied before pitch and yaw
+    let rotation = Vec4::quat_from_euler(Vec3::new(90.0, 90.0, 0.0));
+    assert_close(
+      rotation.quat_rotate(Vec3::new(0.0, 1.0, 0.0)),
+      Vec3::new(1.0, 0.0, 0.0),
+    );
+  }
+
+  #[test]
+  fn euler_angles_round_trip() {
+    for degrees in [
+      Vec3::new(0.0, 0.0, 0.0),
+      Vec3::new(30.0, 45.0, 60.0),
+      Vec3::new(-10.0, 170.0, -120.0),
+      Vec3::new(90.0, 20.0, 0.0),
+    ] {
+      assert_close(Vec4::quat_from_euler(degrees).quat_to_euler(), degrees);
+    }
+  }
+
+  #[test]
+  fn inverse_undoes_rotation() {
+    let rotation = Vec4::quat_from_euler(Vec3::new(30.0, 45.0, 60.0));
+    let vector = Vec3::new(1.0, 2.0, 3.0);
+    assert_close(
+      rotation
+        .quat_inverse()
+        .quat_rotate(rotation.quat_rotate(vector)),
+      vector,
+    );
+  }
+}
@@ -0,0 +1,193 @@
+use crate::math::{Vec3, Vec4};
+use crate::scene::{Scene, SHAPE_NONE};
+
+/// How far apart two collision results may be before they count as a mismatch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+  /// Absolute difference allowed in the `w` component (distance or fraction).
+  pub distance: f32,
+  /// Minimum dot product between the two normalized normals.
+  pub normal_alignment: f32,
+}
+
+impl Default for Tolerance {
+  fn default() -> Self {
+    return Self {
+      distance: 1e-3,
+      normal_alignment: 0.99,
+    };
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mismatch {
+  pub index: usize,
+  pub expected: Vec4,
+  pub actual: Vec4,
+}
+
+/// A group of points swept along a single velocity, the same shape of input
+/// that `compute_shapecast` takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sweep {
+  pub points: Vec<Vec4>,
+  pub velocity: Vec4,
+}
+
+/// Small deterministic xorshift generator so both backends see the same
+/// samples on every run.
+pub struct Sampler {
+  state: u64,
+}
+
+impl Sampler {
+  pub fn new(seed: u64) -> Self {
+    return Self { state: seed.max(1) };
+  }
+
+  pub fn next_f32(&mut self) -> f32 {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 7;
+    self.state ^= self.state << 17;
+    return (self.state >> 40) as f32 / (1u64 << 24) as f32;
+  }
+
+  pub fn range(&mut self, min: f32, max: f32) -> f32 {
+    return min + (max - min) * self.next_f32();
+  }
+
+  pub fn point_in(&mut self, (min, max): (Vec3, Vec3)) -> Vec3 {
+    return Vec3::new(
+      self.range(min.x, max.x),
+      self.range(min.y, max.y),
+      self.range(min.z, max.z),
+    );
+  }
+}
+
+/// Axis aligned box around every bounded shape in the scene, grown by
+/// `margin`.
+pub fn scene_bounds(scene: &Scene, margin: f32) -> (Vec3, Vec3) {
+  let mut min = Vec3::new(f32::MAX, f32::MAX, f32::MAX);
+  let mut max = Vec3::new(f32::MIN, f32::MIN, f32::MIN);
+
+  for (index, properties) in scene.properties().iter().enumerate() {
+    if properties.w == SHAPE_NONE {
+      continue;
+    }
+    let (shape_min, shape_max) = scene.shape_bounds(index);
+    if !shape_min.is_finite() || !shape_max.is_finite() {
+      continue;
+    }
+    min = min.component_min(shape_min);
+    max = max.component_max(shape_max);
+  }
+
+  if min.x > max.x {
+    return (Vec3::ZERO, Vec3::ZERO);
+  }
+  let margin = Vec3::new(margin, margin, margin);
+  return (min - margin, max + margin);
+}
+
+pub fn sample_points(scene: &Scene, count: usize, seed: u64) -> Vec<Vec4> {
+  let bounds = scene_bounds(scene, 2.0);
+  let mut sampler = Sampler::new(seed);
+  return (0..count)
+    .map(|_| sampler.point_in(bounds).extend(0.0))
+    .collect();
+}
+
+pub fn sample_sweeps(
+  scene: &Scene,
+  count: usize,
+  points_per_sweep: usize,
+  max_speed: f32,
+  seed: u64,
+) -> Vec<Sweep> {
+  let bounds = scene_bounds(scene, 2.0);
+  let mut sampler = Sampler::new(seed);
+  return (0..count)
+    .map(|_| {
+      let direction = sampler
+        .point_in((Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0)))
+        .normalized();
+      let speed = sampler.range(0.05, max_speed);
+      let points = (0..points_per_sweep)
+        .map(|_| sampler.point_in(bounds).extend(0.0))
+        .collect();
+      Sweep {
+        points,
+        velocity: (direction * speed).extend(0.0),
+      }
+    })
+    .collect();
+}
+
+/// Every index where `actual` disagrees with `expected`. Normals are only
+/// compared where both gradients are long enough to have a direction.
+pub fn compare_events(expected: &[Vec4], actual: &[Vec4], tolerance: Tolerance) -> Vec<Mismatch> {
+  let mut mismatches = Vec::new();
+  for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+    let distance_matches = (expected.w - actual.w).abs() <= tolerance.distance;
+
+    let expected_normal = expected.xyz();
+    let actual_normal = actual.xyz();
+    let normal_matches = expected_normal.length() < 1e-4
+      || actual_normal.length() < 1e-4
+      || expected_normal.normalized().dot(actual_normal.normalized()) >= tolerance.normal_alignment;
+
+    if !distance_matches || !normal_matches {
+      mismatches.push(Mismatch {
+        index,
+        expected: *expected,
+        actual: *actual,
+      });
+    }
+  }
+
+  for index in expected.len().min(actual.len())..expected.len().max(actual.len()) {
+    mismatches.push(Mismatch {
+      index,
+      expected: expected.get(index).copied().unwrap_or_default(),
+      actual: actual.get(index).copied().unwrap_or_default(),
+    });
+  }
+  return mismatches;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sampler_is_deterministic_and_in_range() {
+    let mut a = Sampler::new(7);
+    let mut b = Sampler::new(7);
+    for _ in 0..1000 {
+      let value = a.next_f32();
+      assert_eq!(value, b.next_f32());
+      assert!((0.0..1.0).contains(&value));
+    }
+  }
+
+  #[test]
+  fn identical_events_match() {
+    let events = vec![Vec4::new(0.0, 1.0, 0.0, 0.5); 4];
+    assert!(compare_events(&events, &events, Tolerance::default()).is_empty());
+  }
+
+  #[test]
+  fn reports_distance_normal_and_length_mismatches() {
+    let expected = vec![Vec4::new(0.0, 1.0, 0.0, 0.5), Vec4::new(0.0, 1.0, 0.0, 0.5)];
+    let actual = vec![
+      Vec4::new(0.0, 1.0, 0.0, 0.6),
+      Vec4::new(1.0, 0.0, 0.0, 0.5),
+      Vec4::ZERO,
+    ];
+
+    let mismatches = compare_events(&expected, &actual, Tolerance::default());
+    let indices: Vec<usize> = mismatches.iter().map(|mismatch| mismatch.index).collect();
+    assert_eq!(indices, vec![0, 1, 2]);
+  }
+}
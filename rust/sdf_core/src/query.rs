@@ -0,0 +1,405 @@
+use crate::math::{Vec3, Vec4};
+use crate::scene::{Scene, ALL_LAYERS};
+use crate::shape::ShapeFlags;
+
+pub const MAX_STEPS: usize = 100;
+pub const SURF_DIST: f32 = 0.01;
+
+/// Mirrors `main` in `collision.glsl`: every point is replaced with
+/// `(normal.xyz, distance)` to the `QueryFilter::SOLID` shapes.
+pub fn compute_collision(scene: &Scene, points: &[Vec4]) -> Vec<Vec4> {
+  return points
+    .iter()
+    .map(|point| {
+      let point = point.xyz();
+      scene.get_normal(point).extend(scene.get_scene_dist(point))
+    })
+    .collect();
+}
+
+/// Which shapes a query sees: those with every `required` flag and none of
+/// the `excluded` ones, on at least one of the collision layers in `mask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryFilter {
+  pub required: ShapeFlags,
+  pub excluded: ShapeFlags,
+  pub mask: u32,
+}
+
+impl QueryFilter {
+  /// Shapes that collide with everything.
+  pub const SOLID: Self = Self {
+    required: ShapeFlags::COLLIDE,
+    excluded: ShapeFlags::TRIGGER
+      .union(ShapeFlags::PLAYER_ONLY)
+      .union(ShapeFlags::GRENADE_ONLY),
+    mask: ALL_LAYERS,
+  };
+  /// What the player collides with.
+  pub const PLAYER: Self = Self {
+    required: ShapeFlags::COLLIDE,
+    excluded: ShapeFlags::TRIGGER.union(ShapeFlags::GRENADE_ONLY),
+    mask: ALL_LAYERS,
+  };
+  /// What grenades collide with.
+  pub const GRENADE: Self = Self {
+    required: ShapeFlags::COLLIDE,
+    excluded: ShapeFlags::TRIGGER.union(ShapeFlags::PLAYER_ONLY),
+    mask: ALL_LAYERS,
+  };
+  /// Trigger shapes, which no other filter sees.
+  pub const TRIGGER: Self = Self {
+    required: ShapeFlags::TRIGGER,
+    excluded: ShapeFlags::empty(),
+    mask: ALL_LAYERS,
+  };
+
+  /// The same filter, only seeing the shapes on the layers in `mask`.
+  pub fn with_mask(mut self, mask: u32) -> Self {
+    self.mask = mask;
+    return self;
+  }
+
+  pub fn accepts(&self, flags: ShapeFlags, layers: u32) -> bool {
+    return flags.contains(self.required)
+      && !flags.intersects(self.excluded)
+      && layers & self.mask != 0;
+  }
+
+  /// `(required, excluded, mask, unused)` as read by `shapecast.glsl`.
+  pub fn pack(&self) -> Vec4 {
+    return Vec4::new(
+      self.required.pack(),
+      self.excluded.pack(),
+      (self.mask & ALL_LAYERS) as f32,
+      0.0,
+    );
+  }
+}
+
+impl Default for QueryFilter {
+  fn default() -> Self {
+    return QueryFilter::SOLID;
+  }
+}
+
+pub const QUERY_POINT: f32 = 0.0;
+pub const QUERY_SWEPT_POINT: f32 = 1.0;
+pub const QUERY_SWEPT_SPHERE: f32 = 2.0;
+
+/// A single question asked of the scene. Every query answers with
+/// `(normal.xyz, w)` where `w` is the distance for `Point` and the fraction of
+/// `velocity` travelled before hitting something for the swept queries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Query {
+  Point {
+    position: Vec3,
+  },
+  SweptPoint {
+    origin: Vec3,
+    velocity: Vec3,
+  },
+  SweptSphere {
+    origin: Vec3,
+    radius: f32,
+    velocity: Vec3,
+  },
+}
+
+impl Query {
+  /// Layout read by `shapecast.glsl`: `(origin.xyz, radius)` followed by
+  /// `(velocity.xyz, kind)`, the kernel reads the query's filter after them.
+  pub fn pack(&self) -> [Vec4; 2] {
+    match *self {
+      Query::Point { position } => {
+        return [position.extend(0.0), Vec3::ZERO.extend(QUERY_POINT)];
+      }
+      Query::SweptPoint { origin, velocity } => {
+        return [origin.extend(0.0), velocity.extend(QUERY_SWEPT_POINT)];
+      }
+      Query::SweptSphere {
+        origin,
+        radius,
+        velocity,
+      } => {
+        return [origin.extend(radius), velocity.extend(QUERY_SWEPT_SPHERE)];
+      }
+    }
+  }
+}
+
+/// Identifies a query inside the batch it was pushed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryHandle(usize);
+
+/// Queries from any number of entities, resolved together in one dispatch.
+#[derive(Debug, Clone, Default)]
+pub struct QueryBatch {
+  queries: Vec<Query>,
+  filters: Vec<QueryFilter>,
+}
+
+impl QueryBatch {
+  pub fn new() -> Self {
+    return Self::default();
+  }
+
+  /// Adds a query that sees the `QueryFilter::SOLID` shapes.
+  pub fn push(&mut self, query: Query) -> QueryHandle {
+    return self.push_filtered(query, QueryFilter::SOLID);
+  }
+
+  pub fn push_filtered(&mut self, query: Query, filter: QueryFilter) -> QueryHandle {
+    self.queries.push(query);
+    self.filters.push(filter);
+    return QueryHandle(self.queries.len() - 1);
+  }
+
+  pub fn queries(&self) -> &[Query] {
+    return &self.queries;
+  }
+
+  /// The filter of every query, in the same order as `queries`.
+  pub fn filters(&self) -> &[QueryFilter] {
+    return &self.filters;
+  }
+
+  pub fn len(&self) -> usize {
+    return self.queries.len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.queries.is_empty();
+  }
+
+  pub fn clear(&mut self) {
+    self.queries.clear();
+    self.filters.clear();
+  }
+}
+
+/// Results of a resolved `QueryBatch`, looked up by the handles it returned.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryResults<T> {
+  results: Vec<T>,
+}
+
+impl<T: Copy> QueryResults<T> {
+  pub fn new(results: Vec<T>) -> Self {
+    return Self { results };
+  }
+
+  pub fn get(&self, handle: QueryHandle) -> Option<T> {
+    return self.results.get(handle.0).copied();
+  }
+
+  pub fn len(&self) -> usize {
+    return self.results.len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.results.is_empty();
+  }
+}
+
+/// Mirrors `main` in `shapecast.glsl`, every query only sees the shapes its
+/// filter in `filters` accepts.
+pub fn compute_queries(scene: &Scene, queries: &[Query], filters: &[QueryFilter]) -> Vec<Vec4> {
+  return queries
+    .iter()
+    .zip(filters)
+    .map(|(query, filter)| match *query {
+      Query::Point { position } => {
+        return scene
+          .get_filtered_normal(position, *filter)
+          .extend(scene.get_filtered_dist(position, *filter));
+      }
+      Query::SweptPoint { origin, velocity } => {
+        return sweep(scene, *filter, origin, 0.0, velocity)
+      }
+      Query::SweptSphere {
+        origin,
+        radius,
+        velocity,
+      } => return sweep(scene, *filter, origin, radius, velocity),
+    })
+    .collect();
+}
+
+/// Every point is swept along `velocity` and replaced with
+/// `(normal.xyz, fraction)`, where a fraction of `1.0` means the point
+/// travelled the full distance without hitting anything.
+pub fn compute_shapecast(scene: &Scene, points: &[Vec4], velocity: Vec4) -> Vec<Vec4> {
+  let queries: Vec<Query> = points
+    .iter()
+    .map(|point| Query::SweptPoint {
+      origin: point.xyz(),
+      velocity: velocity.xyz(),
+    })
+    .collect();
+  return compute_queries(scene, &queries, &vec![QueryFilter::SOLID; queries.len()]);
+}
+
+fn sweep(scene: &Scene, filter: QueryFilter, origin: Vec3, radius: f32, velocity: Vec3) -> Vec4 {
+  let ray_dir = velocity.normalized();
+  let ray_origin = origin + ray_dir * SURF_DIST * 10.0;
+  let vel = velocity.length();
+  let max_dist = vel;
+
+  let mut total_dist = 0.0;
+  for _ in 0..MAX_STEPS {
+    let point = ray_origin + ray_dir * total_dist;
+    let scene_dist = scene.get_filtered_dist(point, filter) - radius;
+    total_dist += scene_dist;
+
+    if total_dist > max_dist || scene_dist < SURF_DIST {
+      break;
+    }
+  }
+
+  if total_dist >= max_dist {
+    return scene
+      .get_filtered_normal(ray_origin + ray_dir * max_dist, filter)
+      .extend(1.0);
+  }
+  return scene
+    .get_filtered_normal(ray_origin + ray_dir * total_dist, filter)
+    .extend(total_dist / vel);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::scene::{FLAGS_SOLID, MAX_SCENE_DIST, SHAPE_CUBE};
+  use crate::shape::{SdfShape, ShapeKind};
+
+  fn floor() -> Scene {
+    let mut scene = Scene::new(0.0);
+    scene
+      .new_shape(
+        Vec4::new(0.0, -1.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(10.0, 1.0, 10.0, SHAPE_CUBE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+    return scene;
+  }
+
+  #[test]
+  fn collision_reports_distance_and_normal() {
+    let scene = floor();
+    let events = compute_collision(&scene, &[Vec4::new(0.0, 2.0, 0.0, 0.0)]);
+
+    assert_eq!(events.len(), 1);
+    assert!((events[0].w - 2.0).abs() < 1e-4);
+    assert!(events[0].xyz().normalized().dot(Vec3::new(0.0, 1.0, 0.0)) > 0.99);
+  }
+
+  #[test]
+  fn shapecast_stops_at_surface() {
+    let scene = floor();
+    let events = compute_shapecast(
+      &scene,
+      &[Vec4::new(0.0, 1.0, 0.0, 0.0)],
+      Vec4::new(0.0, -2.0, 0.0, 0.0),
+    );
+
+    assert!(events[0].w < 1.0);
+    assert!((events[0].w - 0.45).abs() < 0.01);
+  }
+
+  #[test]
+  fn shapecast_misses_report_full_fraction() {
+    let scene = floor();
+    let events = compute_shapecast(
+      &scene,
+      &[Vec4::new(0.0, 1.0, 0.0, 0.0)],
+      Vec4::new(0.0, 0.5, 0.0, 0.0),
+    );
+
+    assert_eq!(events[0].w, 1.0);
+  }
+
+  #[test]
+  fn batch_resolves_mixed_queries_by_handle() {
+    let scene = floor();
+    let mut batch = QueryBatch::new();
+    let point = batch.push(Query::Point {
+      position: Vec3::new(0.0, 2.0, 0.0),
+    });
+    let swept_point = batch.push(Query::SweptPoint {
+      origin: Vec3::new(0.0, 1.0, 0.0),
+      velocity: Vec3::new(0.0, -2.0, 0.0),
+    });
+    let swept_sphere = batch.push(Query::SweptSphere {
+      origin: Vec3::new(0.0, 1.0, 0.0),
+      radius: 0.5,
+      velocity: Vec3::new(0.0, -2.0, 0.0),
+    });
+
+    let results = QueryResults::new(compute_queries(&scene, batch.queries(), batch.filters()));
+    assert_eq!(results.len(), 3);
+    assert!((results.get(point).unwrap().w - 2.0).abs() < 1e-4);
+
+    // the sphere stops one radius earlier along the same sweep
+    let point_fraction = results.get(swept_point).unwrap().w;
+    let sphere_fraction = results.get(swept_sphere).unwrap().w;
+    assert!((point_fraction - sphere_fraction - 0.25).abs() < 0.01);
+  }
+
+  #[test]
+  fn filters_pick_the_shapes_each_query_sees() {
+    let mut scene = floor();
+    for (x, flags) in [
+      (3.0, ShapeFlags::COLLIDE | ShapeFlags::PLAYER_ONLY),
+      (-3.0, ShapeFlags::TRIGGER),
+    ] {
+      let wall = SdfShape::new(
+        ShapeKind::Cube {
+          half_extents: Vec3::new(0.5, 5.0, 5.0),
+        },
+        Vec3::new(x, 0.0, 0.0),
+      );
+      scene.insert_shape(&wall.with_flags(flags)).unwrap();
+    }
+
+    let mut batch = QueryBatch::new();
+    let mut sweeps = Vec::new();
+    for filter in [QueryFilter::PLAYER, QueryFilter::GRENADE] {
+      let query = Query::SweptPoint {
+        origin: Vec3::new(0.0, 1.0, 0.0),
+        velocity: Vec3::new(4.0, 0.0, 0.0),
+      };
+      sweeps.push(batch.push_filtered(query, filter));
+    }
+    let trigger = batch.push_filtered(
+      Query::Point {
+        position: Vec3::new(-1.5, 1.0, 0.0),
+      },
+      QueryFilter::TRIGGER,
+    );
+
+    let results = QueryResults::new(compute_queries(&scene, batch.queries(), batch.filters()));
+    assert!(results.get(sweeps[0]).unwrap().w < 1.0);
+    assert_eq!(results.get(sweeps[1]).unwrap().w, 1.0);
+    assert!((results.get(trigger).unwrap().w - 1.0).abs() < 1e-4);
+    // nothing but trigger queries sees the trigger
+    assert!(scene.get_scene_dist(Vec3::new(-3.0, 1.0, 0.0)) > 0.5);
+  }
+
+  #[test]
+  fn masks_skip_shapes_on_other_layers() {
+    let mut scene = Scene::new(0.0);
+    let decoration = SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::ZERO);
+    scene
+      .insert_shape(&decoration.with_collision_layers(0b10))
+      .unwrap();
+
+    let point = Vec3::new(0.0, 2.0, 0.0);
+    assert!((scene.get_scene_dist(point) - 1.0).abs() < 1e-4);
+    let filter = QueryFilter::PLAYER.with_mask(0b01);
+    assert!(!filter.accepts(ShapeFlags::default(), 0b10));
+    assert_eq!(scene.get_filtered_dist(point, filter), MAX_SCENE_DIST);
+  }
+}
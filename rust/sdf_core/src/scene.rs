@@ -0,0 +1,1164 @@
+use crate::grid::ShapeGrid;
+use crate::group::{self, Group, INSTRUCTION_POP, INSTRUCTION_PUSH, MAX_GROUP_DEPTH};
+use crate::material::{Material, MaterialTable};
+use crate::math::{Vec3, Vec4};
+use crate::query::QueryFilter;
+use crate::sdf::{
+  sdf_box, sdf_capsule, sdf_cone, sdf_cylinder, sdf_plane, sdf_sphere, sdf_torus,
+  smooth_intersection, smooth_subtraction, smooth_union,
+};
+use crate::shape::{PackedShape, SdfShape, ShapeFlags};
+
+/// Shape capacity of a `Scene::new`, use `Scene::with_max_shapes` for more.
+pub const DEFAULT_MAX_SHAPES: usize = 100;
+pub const EPSILON: f32 = 0.01;
+pub const MAX_SCENE_DIST: f32 = 100.0;
+
+pub const SHAPE_NONE: f32 = 0.0;
+pub const SHAPE_SPHERE: f32 = 1.0;
+pub const SHAPE_CUBE: f32 = 2.0;
+pub const SHAPE_CAPSULE: f32 = 3.0;
+pub const SHAPE_TORUS: f32 = 4.0;
+pub const SHAPE_CYLINDER: f32 = 5.0;
+pub const SHAPE_CONE: f32 = 6.0;
+pub const SHAPE_PLANE: f32 = 7.0;
+
+// bits of the flags in `position.w`, which holds them as a float, see
+// `ShapeFlags`
+pub const FLAG_COLLIDE: u32 = 1 << 0;
+pub const FLAG_RENDER: u32 = 1 << 1;
+pub const FLAG_CAST_SHADOW: u32 = 1 << 2;
+pub const FLAG_TRIGGER: u32 = 1 << 3;
+pub const FLAG_PLAYER_ONLY: u32 = 1 << 4;
+pub const FLAG_GRENADE_ONLY: u32 = 1 << 5;
+
+/// `position.w` of a shape that collides, renders and casts shadows.
+pub const FLAGS_SOLID: f32 = (FLAG_COLLIDE | FLAG_RENDER | FLAG_CAST_SHADOW) as f32;
+
+pub const OP_UNION: f32 = 0.0;
+pub const OP_SUBTRACT: f32 = 1.0;
+pub const OP_INTERSECT: f32 = 2.0;
+
+/// Blend radius that makes a shape use the scene's `blend_factor`.
+pub const BLEND_SCENE: f32 = -1.0;
+
+const STALE_SHAPE: &str = "Shape handle refers to a removed shape";
+const STALE_GROUP: &str = "Group address refers to a removed group";
+
+/// Collision layers of a new shape.
+pub const LAYER_DEFAULT: u32 = 1;
+/// Every collision layer, `modifiers.w` holds the layers as a float which
+/// only stores 24 bits exactly.
+pub const ALL_LAYERS: u32 = (1 << 24) - 1;
+
+/// Modifiers of a new shape: no rounding, unioned with the scene's blend on
+/// the default collision layer.
+pub const DEFAULT_MODIFIERS: Vec4 = Vec4::new(0.0, OP_UNION, BLEND_SCENE, LAYER_DEFAULT as f32);
+
+/// Refers to a shape of a `Scene`. Handles of removed shapes stay stale even
+/// after their slot is reused, the scene rejects them instead of touching
+/// whichever shape took the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShapeHandle {
+  slot: u32,
+  generation: u32,
+}
+
+impl ShapeHandle {
+  /// The slot the shape was allocated in, reused once it is removed.
+  pub fn slot(&self) -> usize {
+    return self.slot as usize;
+  }
+
+  pub fn generation(&self) -> u32 {
+    return self.generation;
+  }
+}
+
+/// Shape table in the same layout that is uploaded to the shaders, which
+/// `SdfShape` describes in typed form:
+/// `positions` are `(pos.xyz, flags)`, `rotations` are quaternions,
+/// `properties` are `(dimensions.xyz, type)`, `colors` are `(rgb, material)` and
+/// `modifiers` are `(rounding, operation, blend radius, collision layers)`.
+///
+/// Dimensions are in the shape's local space, which is rotated by the shape's
+/// rotation around its position. What they mean depends on the type:
+/// - sphere: `(radius, _, _)`
+/// - cube: `(half extents)`
+/// - capsule: `(radius, half segment length, _)`, along the y axis
+/// - torus: `(major radius, minor radius, _)`, in the XZ plane
+/// - cylinder: `(radius, half height, _)`, along the y axis
+/// - cone: `(bottom radius, half height, top radius)`, along the y axis
+/// - plane: `(normal)`, through the shape's position
+///
+/// Rounding is subtracted from the distance of every shape, growing it
+/// outwards with rounded edges.
+///
+/// The scene is the union of every `OP_UNION` shape, which `OP_SUBTRACT`
+/// shapes are then carved out of and `OP_INTERSECT` shapes clip, so the result
+/// does not depend on the order of the shapes. Each operation is smoothed by
+/// the shape's blend radius, or by `blend_factor` for `BLEND_SCENE`.
+///
+/// Live shapes are kept packed at the front of the arrays so the shaders only
+/// loop over `num_shapes` entries. Callers refer to shapes by a stable
+/// `ShapeHandle`, whose slot `slots` maps to the shape's current dense index.
+///
+/// Shapes can be collected into a tree of `Group`s, each combined on its own
+/// before being combined with its parent. Groups are addressed like shapes and
+/// there can be as many of them as shapes. Once the scene has groups it is
+/// evaluated through the instruction list `group::flatten` builds from them.
+///
+/// Distance queries use the `ShapeGrid` and instructions built by
+/// `update_caches` while they still match the scene, and rebuild them
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct Scene {
+  pub blend_factor: f32,
+
+  positions: Vec<Vec4>,
+  rotations: Vec<Vec4>,
+  properties: Vec<Vec4>,
+  colors: Vec<Vec4>,
+  modifiers: Vec<Vec4>,
+  /// Group address of every shape, dense like the rows above.
+  shape_groups: Vec<Option<usize>>,
+
+  slots: Vec<Option<usize>>,
+  /// Bumped every time the slot's shape is removed.
+  generations: Vec<u32>,
+  handles: Vec<ShapeHandle>,
+  revision: u64,
+  layout_revision: u64,
+  grid: Option<ShapeGrid>,
+
+  groups: Vec<Option<Group>>,
+  instructions: Vec<Vec4>,
+  instructions_revision: Option<u64>,
+
+  materials: MaterialTable,
+}
+
+impl Default for Scene {
+  fn default() -> Self {
+    return Self::new(1.0);
+  }
+}
+
+impl Scene {
+  pub fn new(blend_factor: f32) -> Self {
+    return Self::with_max_shapes(blend_factor, DEFAULT_MAX_SHAPES);
+  }
+
+  pub fn with_max_shapes(blend_factor: f32, max_shapes: usize) -> Self {
+    return Self {
+      blend_factor,
+      positions: Vec::with_capacity(max_shapes),
+      rotations: Vec::with_capacity(max_shapes),
+      properties: Vec::with_capacity(max_shapes),
+      colors: Vec::with_capacity(max_shapes),
+      modifiers: Vec::with_capacity(max_shapes),
+      shape_groups: Vec::with_capacity(max_shapes),
+      slots: vec![None; max_shapes],
+      generations: vec![0; max_shapes],
+      handles: Vec::with_capacity(max_shapes),
+      revision: 0,
+      layout_revision: 0,
+      grid: None,
+      groups: vec![None; max_shapes],
+      instructions: Vec::new(),
+      instructions_revision: None,
+      materials: MaterialTable::new(),
+    };
+  }
+
+  /// Removes every shape and group and resets the materials.
+  pub fn clear(&mut self) {
+    let revision = self.revision + 1;
+    let layout_revision = self.layout_revision + 1;
+    // handles of the cleared shapes have to stay stale
+    let mut generations = std::mem::take(&mut self.generations);
+    for handle in &self.handles {
+      generations[handle.slot()] = handle.generation.wrapping_add(1);
+    }
+    let mut materials = std::mem::take(&mut self.materials);
+    materials.clear();
+
+    *self = Self::with_max_shapes(self.blend_factor, self.max_shapes());
+    self.generations = generations;
+    self.revision = revision;
+    self.layout_revision = layout_revision;
+    self.materials = materials;
+  }
+
+  pub fn materials(&self) -> &MaterialTable {
+    return &self.materials;
+  }
+
+  pub fn materials_mut(&mut self) -> &mut MaterialTable {
+    return &mut self.materials;
+  }
+
+  /// Most shapes that can be allocated at once, fixed when the scene is made.
+  pub fn max_shapes(&self) -> usize {
+    return self.slots.len();
+  }
+
+  /// Incremented whenever the shape table changes, so GPU copies of it only
+  /// need to be uploaded again when this differs from the uploaded revision.
+  pub fn revision(&self) -> u64 {
+    return self.revision;
+  }
+
+  /// Incremented whenever the grid or the group instructions built from the
+  /// shape table may have to be rebuilt, which is every change but a shape
+  /// moving within the grid cells that already list it.
+  pub fn layout_revision(&self) -> u64 {
+    return self.layout_revision;
+  }
+
+  /// Rebuilds the acceleration grid and the group instructions if the scene
+  /// changed since they were last built.
+  pub fn update_caches(&mut self) {
+    if self.grid().is_none() {
+      self.grid = Some(ShapeGrid::build(self));
+    }
+    if self.instructions_revision != Some(self.layout_revision) {
+      self.instructions = group::flatten(self);
+      self.instructions_revision = Some(self.layout_revision);
+    }
+  }
+
+  /// The acceleration grid, if it is up to date with the scene.
+  pub fn grid(&self) -> Option<&ShapeGrid> {
+    return self.grid.as_ref().filter(|grid| grid.is_current(self));
+  }
+
+  /// The group instructions, if they are up to date with the scene. Empty
+  /// while the scene has no groups.
+  pub fn instructions(&self) -> Option<&[Vec4]> {
+    if self.instructions_revision != Some(self.layout_revision) {
+      return None;
+    }
+    if !self.has_groups() {
+      return Some(&[]);
+    }
+    return Some(&self.instructions);
+  }
+
+  /// Dense, `num_shapes` long.
+  pub fn positions(&self) -> &[Vec4] {
+    return &self.positions;
+  }
+
+  pub fn rotations(&self) -> &[Vec4] {
+    return &self.rotations;
+  }
+
+  pub fn properties(&self) -> &[Vec4] {
+    return &self.properties;
+  }
+
+  pub fn colors(&self) -> &[Vec4] {
+    return &self.colors;
+  }
+
+  pub fn modifiers(&self) -> &[Vec4] {
+    return &self.modifiers;
+  }
+
+  pub fn shape_groups(&self) -> &[Option<usize>] {
+    return &self.shape_groups;
+  }
+
+  /// Handle of the shape stored at each dense index.
+  pub fn handles(&self) -> &[ShapeHandle] {
+    return &self.handles;
+  }
+
+  pub fn num_shapes(&self) -> usize {
+    return self.positions.len();
+  }
+
+  /// Dense index of the shape, `None` once it was removed.
+  pub fn index_of(&self, handle: ShapeHandle) -> Option<usize> {
+    if self.generations.get(handle.slot()) != Some(&handle.generation) {
+      return None;
+    }
+    return self.slots[handle.slot()];
+  }
+
+  fn live_index(&self, handle: ShapeHandle) -> Result<usize, &'static str> {
+    return self.index_of(handle).ok_or(STALE_SHAPE);
+  }
+
+  pub fn insert_shape(&mut self, shape: &SdfShape) -> Result<ShapeHandle, &'static str> {
+    return self.insert_packed(shape.pack());
+  }
+
+  /// Replaces every property of the shape, its group is kept. Setting the
+  /// shape it already is keeps the revision, so caches and uploads survive.
+  pub fn set_shape(&mut self, handle: ShapeHandle, shape: &SdfShape) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+
+    let packed = shape.pack();
+    let unchanged = self.positions[index] == packed.position
+      && self.rotations[index] == packed.rotation
+      && self.properties[index] == packed.properties
+      && self.colors[index] == packed.color
+      && self.modifiers[index] == packed.modifiers;
+    if unchanged {
+      return Ok(());
+    }
+    let moved =
+      self.properties[index].w == packed.properties.w && self.modifiers[index] == packed.modifiers;
+
+    self.positions[index] = packed.position;
+    self.rotations[index] = packed.rotation;
+    self.properties[index] = packed.properties;
+    self.colors[index] = packed.color;
+    self.modifiers[index] = packed.modifiers;
+    if moved {
+      self.moved(index);
+    } else {
+      self.changed();
+    }
+    return Ok(());
+  }
+
+  /// `None` if the shape was removed or has no known type.
+  pub fn shape(&self, handle: ShapeHandle) -> Option<SdfShape> {
+    let index = self.index_of(handle)?;
+    return SdfShape::unpack(&PackedShape {
+      position: self.positions[index],
+      rotation: self.rotations[index],
+      properties: self.properties[index],
+      color: self.colors[index],
+      modifiers: self.modifiers[index],
+    });
+  }
+
+  /// Raw form of `insert_shape` with the default modifiers.
+  pub fn new_shape(
+    &mut self,
+    position: Vec4,
+    rotation: Vec4,
+    properties: Vec4,
+    color: Vec4,
+  ) -> Result<ShapeHandle, &'static str> {
+    return self.insert_packed(PackedShape {
+      position,
+      rotation,
+      properties,
+      color,
+      modifiers: DEFAULT_MODIFIERS,
+    });
+  }
+
+  fn insert_packed(&mut self, packed: PackedShape) -> Result<ShapeHandle, &'static str> {
+    if self.num_shapes() == self.max_shapes() {
+      return Err("Cannot allocate new shape, maximum amount of shapes allocated");
+    }
+
+    let Some(slot) = self.slots.iter().position(|slot| slot.is_none()) else {
+      return Err("Cannot allocate new shape, no shape slot available");
+    };
+
+    let handle = ShapeHandle {
+      slot: slot as u32,
+      generation: self.generations[slot],
+    };
+    self.slots[slot] = Some(self.positions.len());
+    self.handles.push(handle);
+    self.positions.push(packed.position);
+    self.rotations.push(packed.rotation);
+    self.properties.push(packed.properties);
+    self.colors.push(packed.color);
+    self.modifiers.push(packed.modifiers);
+    self.shape_groups.push(None);
+    self.changed();
+
+    return Ok(handle);
+  }
+
+  /// Raw form of `set_shape` that keeps the shape's modifiers.
+  pub fn update_shape(
+    &mut self,
+    handle: ShapeHandle,
+    position: Vec4,
+    rotation: Vec4,
+    properties: Vec4,
+    color: Vec4,
+  ) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+    let moved = self.properties[index].w == properties.w;
+
+    self.positions[index] = position;
+    self.rotations[index] = rotation;
+    self.properties[index] = properties;
+    self.colors[index] = color;
+    if moved {
+      self.moved(index);
+    } else {
+      self.changed();
+    }
+    return Ok(());
+  }
+
+  pub fn set_modifiers(
+    &mut self,
+    handle: ShapeHandle,
+    modifiers: Vec4,
+  ) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+
+    self.modifiers[index] = modifiers;
+    self.changed();
+    return Ok(());
+  }
+
+  /// Changes how the shape combines with the rest of the scene, keeping its
+  /// rounding.
+  pub fn set_operation(
+    &mut self,
+    handle: ShapeHandle,
+    operation: f32,
+    blend_radius: f32,
+  ) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+
+    self.modifiers[index].y = operation;
+    self.modifiers[index].z = blend_radius;
+    self.changed();
+    return Ok(());
+  }
+
+  /// Moves the shape onto the collision layers set in `layers`, queries only
+  /// see it when their `QueryFilter::mask` shares one of them.
+  pub fn set_collision_layers(
+    &mut self,
+    handle: ShapeHandle,
+    layers: u32,
+  ) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+
+    self.modifiers[index].w = (layers & ALL_LAYERS) as f32;
+    self.changed();
+    return Ok(());
+  }
+
+  /// How far the shape at dense `index` blends into the shapes it combines
+  /// with.
+  pub fn blend_radius(&self, index: usize) -> f32 {
+    return self.resolve_blend_radius(self.modifiers[index].z);
+  }
+
+  fn resolve_blend_radius(&self, blend_radius: f32) -> f32 {
+    if blend_radius < 0.0 {
+      return self.blend_factor.max(0.0);
+    }
+    return blend_radius;
+  }
+
+  pub fn remove_shape(&mut self, handle: ShapeHandle) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+
+    // the last shape moves into the hole to keep the arrays packed
+    self.positions.swap_remove(index);
+    self.rotations.swap_remove(index);
+    self.properties.swap_remove(index);
+    self.colors.swap_remove(index);
+    self.modifiers.swap_remove(index);
+    self.shape_groups.swap_remove(index);
+    self.handles.swap_remove(index);
+    if let Some(moved) = self.handles.get(index) {
+      self.slots[moved.slot()] = Some(index);
+    }
+    self.slots[handle.slot()] = None;
+    self.generations[handle.slot()] = handle.generation.wrapping_add(1);
+    self.changed();
+    return Ok(());
+  }
+
+  pub fn has_groups(&self) -> bool {
+    return self.groups.iter().any(|group| group.is_some());
+  }
+
+  pub fn group(&self, address: usize) -> Option<&Group> {
+    return self.groups.get(address).and_then(|group| group.as_ref());
+  }
+
+  /// Every live group with its address.
+  pub fn groups(&self) -> impl Iterator<Item = (usize, &Group)> {
+    return self
+      .groups
+      .iter()
+      .enumerate()
+      .filter_map(|(address, group)| Some((address, group.as_ref()?)));
+  }
+
+  /// Adds an empty group to `parent`, or to the scene itself for `None`.
+  pub fn new_group(
+    &mut self,
+    parent: Option<usize>,
+    operation: f32,
+    blend_radius: f32,
+  ) -> Result<usize, &'static str> {
+    let mut depth = 1;
+    let mut ancestor = parent;
+    while let Some(address) = ancestor {
+      let Some(group) = self.group(address) else {
+        return Err("Cannot allocate new group, parent group does not exist");
+      };
+      depth += 1;
+      ancestor = group.parent;
+    }
+    if depth > MAX_GROUP_DEPTH {
+      return Err("Cannot allocate new group, groups are nested too deep");
+    }
+
+    let Some(address) = self.groups.iter().position(|group| group.is_none()) else {
+      return Err("Cannot allocate new group, maximum amount of groups allocated");
+    };
+
+    self.groups[address] = Some(Group {
+      parent,
+      operation,
+      blend_radius,
+    });
+    self.changed();
+    return Ok(address);
+  }
+
+  /// Changes how the group combines with its parent.
+  pub fn set_group_operation(
+    &mut self,
+    address: usize,
+    operation: f32,
+    blend_radius: f32,
+  ) -> Result<(), &'static str> {
+    let Some(Some(group)) = self.groups.get_mut(address) else {
+      return Err(STALE_GROUP);
+    };
+
+    group.operation = operation;
+    group.blend_radius = blend_radius;
+    self.changed();
+    return Ok(());
+  }
+
+  /// Moves the shape into `group`, or back into the scene itself for `None`.
+  pub fn set_shape_group(
+    &mut self,
+    handle: ShapeHandle,
+    group: Option<usize>,
+  ) -> Result<(), &'static str> {
+    let index = self.live_index(handle)?;
+    if let Some(group) = group {
+      if self.group(group).is_none() {
+        return Err(STALE_GROUP);
+      }
+    }
+
+    self.shape_groups[index] = group;
+    self.changed();
+    return Ok(());
+  }
+
+  /// Removes the group, its shapes and child groups move up into its parent.
+  pub fn remove_group(&mut self, address: usize) -> Result<(), &'static str> {
+    let Some(removed) = self.group(address).copied() else {
+      return Err(STALE_GROUP);
+    };
+
+    for group in self.shape_groups.iter_mut() {
+      if *group == Some(address) {
+        *group = removed.parent;
+      }
+    }
+    for group in self.groups.iter_mut().flatten() {
+      if group.parent == Some(address) {
+        group.parent = removed.parent;
+      }
+    }
+    self.groups[address] = None;
+    self.changed();
+    return Ok(());
+  }
+
+  /// Marks the shape table changed, along with everything built from it.
+  fn changed(&mut self) {
+    self.revision += 1;
+    self.layout_revision += 1;
+  }
+
+  /// `changed` for a shape that kept its type and modifiers, which only
+  /// affects the grid when it now reaches cells that do not list it. The
+  /// group instructions never depend on where shapes are.
+  fn moved(&mut self, index: usize) {
+    self.revision += 1;
+    let listed = self.grid().is_some_and(|grid| grid.lists(self, index));
+    if !self.has_groups() && !listed {
+      self.layout_revision += 1;
+    }
+  }
+
+  /// Distance to the `QueryFilter::SOLID` shapes.
+  pub fn get_scene_dist(&self, point: Vec3) -> f32 {
+    return self.get_filtered_dist(point, QueryFilter::SOLID);
+  }
+
+  /// Mirrors `get_scene_dist` in the compute shaders: every shape `filter`
+  /// accepts is combined into the result, only looking at the shapes of the
+  /// point's grid cell when there is a current grid. Scenes with groups run
+  /// their instructions instead.
+  pub fn get_filtered_dist(&self, point: Vec3, filter: QueryFilter) -> f32 {
+    if self.has_groups() {
+      return match self.instructions() {
+        Some(instructions) => self.run_instructions(point, filter, instructions),
+        None => self.run_instructions(point, filter, &group::flatten(self)),
+      };
+    }
+
+    if let Some(grid) = self.grid() {
+      if let Some(candidates) = grid.candidates(point) {
+        let indices = candidates.iter().map(|index| *index as usize);
+        let dist = self.combine_shapes(point, filter, indices);
+        return dist.min(grid.reach());
+      }
+    }
+    return self.combine_shapes(point, filter, 0..self.num_shapes());
+  }
+
+  fn combine_shapes(
+    &self,
+    point: Vec3,
+    filter: QueryFilter,
+    indices: impl Iterator<Item = usize> + Clone,
+  ) -> f32 {
+    let mut output_dist = MAX_SCENE_DIST;
+
+    // unions first, so carving and clipping shapes apply to all of them
+    for carving in [false, true] {
+      for index in indices.clone() {
+        if (self.modifiers[index].y != OP_UNION) == carving {
+          output_dist = self.add_shape(output_dist, point, filter, index);
+        }
+      }
+    }
+    return output_dist;
+  }
+
+  /// Mirrors `run_instructions` in the compute shaders.
+  fn run_instructions(&self, point: Vec3, filter: QueryFilter, instructions: &[Vec4]) -> f32 {
+    let mut stack = [MAX_SCENE_DIST; MAX_GROUP_DEPTH + 1];
+    let mut depth = 0;
+    for instruction in instructions {
+      if instruction.x == INSTRUCTION_PUSH {
+        depth += 1;
+        stack[depth] = MAX_SCENE_DIST;
+      } else if instruction.x == INSTRUCTION_POP {
+        let k = self.resolve_blend_radius(instruction.z);
+        stack[depth - 1] = apply_operation(stack[depth - 1], stack[depth], instruction.y, k);
+        depth -= 1;
+      } else {
+        let index = instruction.y as usize;
+        stack[depth] = self.add_shape(stack[depth], point, filter, index);
+      }
+    }
+    return stack[0];
+  }
+
+  fn add_shape(&self, output_dist: f32, point: Vec3, filter: QueryFilter, index: usize) -> f32 {
+    let position = self.positions[index];
+    let properties = self.properties[index];
+    let modifiers = self.modifiers[index];
+    let flags = ShapeFlags::unpack(position.w);
+    if properties.w == SHAPE_NONE || !filter.accepts(flags, modifiers.w as u32) {
+      return output_dist;
+    }
+
+    let rotation = self.rotations[index];
+    let dist = shape_dist(point, position.xyz(), rotation, properties) - modifiers.x;
+    return apply_operation(output_dist, dist, modifiers.y, self.blend_radius(index));
+  }
+
+  /// World space axis aligned box that contains the whole shape, infinite for
+  /// planes and intersections.
+  pub fn shape_bounds(&self, index: usize) -> (Vec3, Vec3) {
+    let properties = self.properties[index];
+    let size = properties.xyz().abs();
+    // intersections clip everything outside of them, however far away
+    let extent = if properties.w == SHAPE_PLANE || self.modifiers[index].y == OP_INTERSECT {
+      Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY)
+    } else if properties.w == SHAPE_SPHERE {
+      Vec3::new(size.x, size.x, size.x)
+    } else if properties.w == SHAPE_CUBE {
+      size
+    } else if properties.w == SHAPE_CAPSULE {
+      Vec3::new(size.x, size.y + size.x, size.x)
+    } else if properties.w == SHAPE_TORUS {
+      Vec3::new(size.x + size.y, size.y, size.x + size.y)
+    } else if properties.w == SHAPE_CYLINDER {
+      Vec3::new(size.x, size.y, size.x)
+    } else if properties.w == SHAPE_CONE {
+      let radius = size.x.max(size.z);
+      Vec3::new(radius, size.y, radius)
+    } else {
+      let size = size.max_element();
+      Vec3::new(size, size, size)
+    };
+
+    let rounding = self.modifiers[index].x.max(0.0);
+    let mut extent = extent + Vec3::new(rounding, rounding, rounding);
+    if extent.is_finite() {
+      // the box around the rotated local box
+      let rotation = self.rotations[index];
+      extent = rotation.quat_rotate(Vec3::new(extent.x, 0.0, 0.0)).abs()
+        + rotation.quat_rotate(Vec3::new(0.0, extent.y, 0.0)).abs()
+        + rotation.quat_rotate(Vec3::new(0.0, 0.0, extent.z)).abs();
+    }
+
+    let position = self.positions[index].xyz();
+    return (position - extent, position + extent);
+  }
+
+  /// Normal of the `QueryFilter::SOLID` shapes.
+  pub fn get_normal(&self, point: Vec3) -> Vec3 {
+    return self.get_filtered_normal(point, QueryFilter::SOLID);
+  }
+
+  /// Dense index of the union shape `filter` accepts whose own surface is
+  /// closest to `point`, the shape a surface near `point` belongs to. Only
+  /// looks at the shapes of the point's grid cell when there is a current
+  /// grid, like `get_filtered_dist`. Mirrors `surface_shape` in the renderer.
+  pub fn surface_shape(&self, point: Vec3, filter: QueryFilter) -> Option<usize> {
+    if let Some(candidates) = self.grid().and_then(|grid| grid.candidates(point)) {
+      let indices = candidates.iter().map(|index| *index as usize);
+      return self.nearest_union(point, filter, indices);
+    }
+    return self.nearest_union(point, filter, 0..self.num_shapes());
+  }
+
+  fn nearest_union(
+    &self,
+    point: Vec3,
+    filter: QueryFilter,
+    indices: impl Iterator<Item = usize>,
+  ) -> Option<usize> {
+    let mut closest = None;
+    let mut closest_dist = f32::INFINITY;
+    for index in indices {
+      let position = self.positions[index];
+      let properties = self.properties[index];
+      let modifiers = self.modifiers[index];
+      let flags = ShapeFlags::unpack(position.w);
+      if properties.w == SHAPE_NONE
+        || modifiers.y != OP_UNION
+        || !filter.accepts(flags, modifiers.w as u32)
+      {
+        continue;
+      }
+
+      let rotation = self.rotations[index];
+      let dist = shape_dist(point, position.xyz(), rotation, properties) - modifiers.x;
+      if dist < closest_dist {
+        closest = Some(index);
+        closest_dist = dist;
+      }
+    }
+    return closest;
+  }
+
+  /// Material of the surface near `point`, the default material when no
+  /// shape is there.
+  pub fn material_at(&self, point: Vec3, filter: QueryFilter) -> Material {
+    return match self.surface_shape(point, filter) {
+      Some(index) => self.materials.get(self.colors[index].w as u32),
+      None => self.materials.get(0),
+    };
+  }
+
+  /// Central difference gradient, left unnormalized like the compute shaders.
+  pub fn get_filtered_normal(&self, point: Vec3, filter: QueryFilter) -> Vec3 {
+    let x = Vec3::new(EPSILON, 0.0, 0.0);
+    let y = Vec3::new(0.0, EPSILON, 0.0);
+    let z = Vec3::new(0.0, 0.0, EPSILON);
+    let dist = |point| self.get_filtered_dist(point, filter);
+    return Vec3::new(
+      dist(point + x) - dist(point - x),
+      dist(point + y) - dist(point - y),
+      dist(point + z) - dist(point - z),
+    );
+  }
+}
+
+/// Combines a shape's distance into the distance of the shapes before it.
+pub fn apply_operation(output_dist: f32, dist: f32, operation: f32, k: f32) -> f32 {
+  if operation == OP_SUBTRACT {
+    return smooth_subtraction(output_dist, dist, k);
+  }
+
+  if operation == OP_INTERSECT {
+    return smooth_intersection(output_dist, dist, k);
+  }
+
+  return smooth_union(output_dist, dist, k);
+}
+
+/// Distance to a single shape, which is evaluated in its local space.
+pub fn shape_dist(point: Vec3, position: Vec3, rotation: Vec4, properties: Vec4) -> f32 {
+  let local = rotation.quat_inverse().quat_rotate(point - position);
+
+  if properties.w == SHAPE_SPHERE {
+    return sdf_sphere(local, properties.x);
+  }
+
+  if properties.w == SHAPE_CUBE {
+    return sdf_box(local, properties.xyz());
+  }
+
+  if properties.w == SHAPE_CAPSULE {
+    return sdf_capsule(local, properties.x, properties.y);
+  }
+
+  if properties.w == SHAPE_TORUS {
+    return sdf_torus(local, properties.x, properties.y);
+  }
+
+  if properties.w == SHAPE_CYLINDER {
+    return sdf_cylinder(local, properties.x, properties.y);
+  }
+
+  if properties.w == SHAPE_CONE {
+    return sdf_cone(local, properties.x, properties.y, properties.z);
+  }
+
+  if properties.w == SHAPE_PLANE {
+    return sdf_plane(local, properties.xyz());
+  }
+
+  return MAX_SCENE_DIST;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::shape::{Operation, ShapeFlags, ShapeKind};
+
+  fn sphere(x: f32, y: f32, z: f32, r: f32) -> (Vec4, Vec4) {
+    return (
+      Vec4::new(x, y, z, FLAGS_SOLID),
+      Vec4::new(r, 0.0, 0.0, SHAPE_SPHERE),
+    );
+  }
+
+  #[test]
+  fn typed_shapes_round_trip_through_the_scene() {
+    let mut scene = Scene::default();
+    let sphere = SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::new(0.0, 1.0, 0.0))
+      .with_operation(Operation::Subtract, Some(0.25));
+    let address = scene.insert_shape(&sphere).unwrap();
+    assert_eq!(scene.shape(address), Some(sphere));
+    assert_eq!(scene.modifiers()[0], Vec4::new(0.0, OP_SUBTRACT, 0.25, 1.0));
+
+    let cube = SdfShape::new(
+      ShapeKind::Cube {
+        half_extents: Vec3::new(1.0, 2.0, 3.0),
+      },
+      Vec3::ZERO,
+    )
+    .with_flags(ShapeFlags::RENDER);
+    scene.set_shape(address, &cube).unwrap();
+    assert_eq!(scene.shape(address), Some(cube));
+    scene.remove_shape(address).unwrap();
+    assert_eq!(scene.shape(address), None);
+  }
+
+  #[test]
+  fn empty_scene_is_far_away() {
+    let scene = Scene::default();
+    assert_eq!(scene.get_scene_dist(Vec3::ZERO), MAX_SCENE_DIST);
+  }
+
+  #[test]
+  fn single_sphere_distance() {
+    let mut scene = Scene::new(0.0);
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+
+    assert!((scene.get_scene_dist(Vec3::new(3.0, 0.0, 0.0)) - 2.0).abs() < 1e-5);
+    assert!((scene.get_scene_dist(Vec3::ZERO) + 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn box_distance() {
+    let mut scene = Scene::new(0.0);
+    scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(1.0, 2.0, 3.0, SHAPE_CUBE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+
+    assert!((scene.get_scene_dist(Vec3::new(0.0, 5.0, 0.0)) - 3.0).abs() < 1e-5);
+    assert!((scene.get_scene_dist(Vec3::ZERO) + 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn rotated_shapes_are_evaluated_in_local_space() {
+    let mut scene = Scene::new(0.0);
+    let address = scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::quat_from_euler(Vec3::new(0.0, 0.0, 90.0)),
+        Vec4::new(3.0, 1.0, 1.0, SHAPE_CUBE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+
+    // the long axis now points along y
+    assert!((scene.get_scene_dist(Vec3::new(0.0, 5.0, 0.0)) - 2.0).abs() < 1e-4);
+    assert!((scene.get_scene_dist(Vec3::new(5.0, 0.0, 0.0)) - 4.0).abs() < 1e-4);
+
+    let (min, max) = scene.shape_bounds(scene.index_of(address).unwrap());
+    assert!((max - Vec3::new(1.0, 3.0, 1.0)).length() < 1e-4);
+    assert!((min + Vec3::new(1.0, 3.0, 1.0)).length() < 1e-4);
+  }
+
+  #[test]
+  fn subtraction_carves_regardless_of_order() {
+    let mut scene = Scene::new(0.0);
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    let first = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    scene.set_operation(first, OP_SUBTRACT, 0.0).unwrap();
+    scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(2.0, 2.0, 2.0, SHAPE_CUBE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+
+    // hollow box, the center is outside and the walls are inside
+    assert!((scene.get_scene_dist(Vec3::ZERO) - 1.0).abs() < 1e-5);
+    assert!(scene.get_scene_dist(Vec3::new(1.5, 0.0, 0.0)) < 0.0);
+  }
+
+  #[test]
+  fn intersection_clips_the_scene() {
+    let mut scene = Scene::new(0.0);
+    scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAGS_SOLID),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(2.0, 2.0, 2.0, SHAPE_CUBE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+    let (position, properties) = sphere(2.0, 0.0, 0.0, 1.0);
+    let clip = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    scene.set_operation(clip, OP_INTERSECT, 0.0).unwrap();
+    scene.update_caches();
+
+    assert!(scene.get_scene_dist(Vec3::new(1.5, 0.0, 0.0)) < 0.0);
+    assert!(scene.get_scene_dist(Vec3::new(-1.5, 0.0, 0.0)) > 0.0);
+  }
+
+  #[test]
+  fn non_colliding_shapes_are_ignored() {
+    let mut scene = Scene::new(0.0);
+    scene
+      .new_shape(
+        Vec4::new(0.0, 0.0, 0.0, FLAG_RENDER as f32),
+        Vec4::QUAT_IDENTITY,
+        Vec4::new(1.0, 0.0, 0.0, SHAPE_SPHERE),
+        Vec4::ZERO,
+      )
+      .unwrap();
+
+    assert_eq!(scene.get_scene_dist(Vec3::ZERO), MAX_SCENE_DIST);
+  }
+
+  #[test]
+  fn surfaces_use_the_nearest_shapes_material() {
+    let mut scene = Scene::new(0.5);
+    let ice = Material {
+      friction: 0.0,
+      ..Material::default()
+    };
+    let id = scene.materials_mut().push(ice).unwrap();
+    scene
+      .insert_shape(&SdfShape::new(ShapeKind::Sphere { radius: 1.0 }, Vec3::ZERO).with_material(id))
+      .unwrap();
+    scene
+      .insert_shape(&SdfShape::new(
+        ShapeKind::Sphere { radius: 1.0 },
+        Vec3::new(3.0, 0.0, 0.0),
+      ))
+      .unwrap();
+
+    assert_eq!(
+      scene.material_at(Vec3::new(-1.0, 0.0, 0.0), QueryFilter::SOLID),
+      ice
+    );
+    assert_eq!(
+      scene.material_at(Vec3::new(4.0, 0.0, 0.0), QueryFilter::SOLID),
+      Material::default()
+    );
+  }
+
+  #[test]
+  fn normal_points_away_from_surface() {
+    let mut scene = Scene::new(0.5);
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+
+    let normal = scene.get_normal(Vec3::new(0.0, 1.0, 0.0)).normalized();
+    assert!(normal.dot(Vec3::new(0.0, 1.0, 0.0)) > 0.99);
+  }
+
+  #[test]
+  fn freed_slots_are_reused() {
+    let mut scene = Scene::default();
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    let a = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    let b = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    scene.remove_shape(a).unwrap();
+
+    let reused = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    assert_eq!(reused.slot(), a.slot());
+    assert_ne!(a, b);
+    assert_eq!(scene.num_shapes(), 2);
+  }
+
+  #[test]
+  fn stale_handles_are_rejected() {
+    let mut scene = Scene::default();
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    let stale = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    scene.remove_shape(stale).unwrap();
+    let reused = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+
+    // the slot now holds `reused`, which stale uses must not touch
+    assert!(scene.remove_shape(stale).is_err());
+    assert!(scene.set_operation(stale, OP_SUBTRACT, 0.0).is_err());
+    assert_eq!(scene.index_of(stale), None);
+    assert_eq!(scene.index_of(reused), Some(0));
+
+    scene.clear();
+    assert!(scene.remove_shape(reused).is_err());
+  }
+
+  #[test]
+  fn removal_keeps_shapes_packed() {
+    let mut scene = Scene::default();
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    let a = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    let b = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    let c = scene
+      .new_shape(
+        position,
+        Vec4::QUAT_IDENTITY,
+        properties,
+        Vec4::new(1.0, 0.0, 0.0, 0.0),
+      )
+      .unwrap();
+
+    scene.remove_shape(a).unwrap();
+    assert_eq!(scene.positions().len(), 2);
+    assert_eq!(scene.index_of(a), None);
+
+    // `c` moved into the freed dense index but kept its handle
+    let index = scene.index_of(c).unwrap();
+    assert_eq!(scene.colors()[index], Vec4::new(1.0, 0.0, 0.0, 0.0));
+    assert_eq!(scene.handles()[index], c);
+    assert!(scene.index_of(b).is_some());
+  }
+
+  #[test]
+  fn capacity_is_configurable() {
+    let mut scene = Scene::with_max_shapes(0.0, 2000);
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+    for _ in 0..2000 {
+      scene
+        .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+        .unwrap();
+    }
+
+    assert_eq!(scene.num_shapes(), 2000);
+    assert!(scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .is_err());
+
+    scene.clear();
+    assert_eq!(scene.max_shapes(), 2000);
+  }
+
+  #[test]
+  fn revision_changes_with_the_shape_table() {
+    let mut scene = Scene::default();
+    let (position, properties) = sphere(0.0, 0.0, 0.0, 1.0);
+
+    let mut revisions = vec![scene.revision()];
+    let address = scene
+      .new_shape(position, Vec4::QUAT_IDENTITY, properties, Vec4::ZERO)
+      .unwrap();
+    revisions.push(scene.revision());
+    scene
+      .update_shape(
+        address,
+        position,
+        Vec4::QUAT_IDENTITY,
+        properties,
+        Vec4::ZERO,
+      )
+      .unwrap();
+    revisions.push(scene.revision());
+    scene.remove_shape(address).unwrap();
+    revisions.push(scene.revision());
+    scene.clear();
+    revisions.push(scene.revision());
+
+    revisions.dedup();
+    assert_eq!(revisions.len(), 5);
+  }
+
+  #[test]
+  fn setting_an_unchanged_shape_keeps_the_revision() {
+    let mut scene = Scene::default();
+    let grenade = SdfShape::new(ShapeKind::Sphere { radius: 0.01 }, Vec3::ZERO);
+    let address = scene.insert_shape(&grenade).unwrap();
+    let revision = scene.revision();
+
+    scene.set_shape(address, &grenade).unwrap();
+    assert_eq!(scene.revision(), revision);
+    let moved = SdfShape::new(ShapeKind::Sphere { radius: 0.01 }, Vec3::new(0.0, 1.0, 0.0));
+    scene.set_shape(address, &moved).unwrap();
+    assert_ne!(scene.revision(), revision);
+  }
+}
@@ -0,0 +1,132 @@
+use crate::math::Vec3;
+
+// <SDF Primitives>
+pub fn sdf_sphere(point: Vec3, r: f32) -> f32 {
+  return point.length() - r;
+}
+
+pub fn sdf_box(point: Vec3, bounds: Vec3) -> f32 {
+  let q = point.abs() - bounds;
+  return q.max(0.0).length() + q.max_element().min(0.0);
+}
+
+/// Vertical capsule, `h` is half the length of its center segment.
+pub fn sdf_capsule(point: Vec3, r: f32, h: f32) -> f32 {
+  let y = point.y - point.y.clamp(-h, h);
+  return Vec3::new(point.x, y, point.z).length() - r;
+}
+
+/// Torus lying in the XZ plane.
+pub fn sdf_torus(point: Vec3, major: f32, minor: f32) -> f32 {
+  return length2(length2(point.x, point.z) - major, point.y) - minor;
+}
+
+/// Vertical capped cylinder, `h` is half its height.
+pub fn sdf_cylinder(point: Vec3, r: f32, h: f32) -> f32 {
+  let dx = length2(point.x, point.z) - r;
+  let dy = point.y.abs() - h;
+  return dx.max(dy).min(0.0) + length2(dx.max(0.0), dy.max(0.0));
+}
+
+/// Vertical capped cone with radius `r1` at the bottom and `r2` at the top,
+/// `h` is half its height.
+pub fn sdf_cone(point: Vec3, r1: f32, h: f32, r2: f32) -> f32 {
+  let (qx, qy) = (length2(point.x, point.z), point.y);
+  let (k1x, k1y) = (r2, h);
+  let (k2x, k2y) = (r2 - r1, 2.0 * h);
+  let cax = qx - qx.min(if qy < 0.0 { r1 } else { r2 });
+  let cay = qy.abs() - h;
+  let t = (((k1x - qx) * k2x + (k1y - qy) * k2y) / (k2x * k2x + k2y * k2y)).clamp(0.0, 1.0);
+  let cbx = qx - k1x + k2x * t;
+  let cby = qy - k1y + k2y * t;
+  let s = if cbx < 0.0 && cay < 0.0 { -1.0 } else { 1.0 };
+  return s * (cax * cax + cay * cay).min(cbx * cbx + cby * cby).sqrt();
+}
+
+/// Infinite plane through the origin facing `normal`.
+pub fn sdf_plane(point: Vec3, normal: Vec3) -> f32 {
+  return point.dot(normal.normalized());
+}
+// <\SDF Primitives>
+
+// <SDF Operations>
+pub fn smooth_union(dist1: f32, dist2: f32, k: f32) -> f32 {
+  if k <= 0.0 {
+    return dist1.min(dist2);
+  }
+  let h = (0.5 + 0.5 * (dist2 - dist1) / k).clamp(0.0, 1.0);
+  return mix(dist2, dist1, h) - k * h * (1.0 - h);
+}
+
+/// Carves `dist2` out of `dist1`.
+pub fn smooth_subtraction(dist1: f32, dist2: f32, k: f32) -> f32 {
+  if k <= 0.0 {
+    return dist1.max(-dist2);
+  }
+  let h = (0.5 - 0.5 * (dist1 + dist2) / k).clamp(0.0, 1.0);
+  return mix(dist1, -dist2, h) + k * h * (1.0 - h);
+}
+
+pub fn smooth_intersection(dist1: f32, dist2: f32, k: f32) -> f32 {
+  if k <= 0.0 {
+    return dist1.max(dist2);
+  }
+  let h = (0.5 - 0.5 * (dist2 - dist1) / k).clamp(0.0, 1.0);
+  return mix(dist2, dist1, h) + k * h * (1.0 - h);
+}
+// <\SDF Operations>
+
+pub fn mix(a: f32, b: f32, t: f32) -> f32 {
+  return a * (1.0 - t) + b * t;
+}
+
+fn length2(x: f32, y: f32) -> f32 {
+  return (x * x + y * y).sqrt();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn primitives_measure_from_their_surface() {
+    let up = Vec3::new(0.0, 1.0, 0.0);
+    assert!((sdf_capsule(up * 3.0, 0.5, 1.0) - 1.5).abs() < 1e-5);
+    assert!((sdf_torus(Vec3::new(2.0, 1.0, 0.0), 2.0, 0.5) - 0.5).abs() < 1e-5);
+    assert!((sdf_cylinder(Vec3::new(3.0, 0.0, 0.0), 1.0, 2.0) - 2.0).abs() < 1e-5);
+    assert!((sdf_cylinder(up * 4.0, 1.0, 2.0) - 2.0).abs() < 1e-5);
+    assert!((sdf_cone(up * 3.0, 1.0, 1.0, 0.0) - 2.0).abs() < 1e-5);
+    assert!((sdf_cone(Vec3::new(2.0, -1.0, 0.0), 1.0, 1.0, 0.0) - 1.0).abs() < 1e-5);
+    assert!((sdf_plane(up * 2.0, up * 5.0) - 2.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn sharp_operations_match_min_and_max() {
+    assert_eq!(smooth_union(1.0, 2.0, 0.0), 1.0);
+    assert_eq!(smooth_subtraction(-1.0, -0.5, 0.0), 0.5);
+    assert_eq!(smooth_subtraction(-1.0, 3.0, 0.0), -1.0);
+    assert_eq!(smooth_intersection(1.0, 2.0, 0.0), 2.0);
+  }
+
+  #[test]
+  fn smooth_operations_only_change_the_blend_region() {
+    // further apart than the radius, the sharp result is kept
+    assert_eq!(smooth_union(1.0, 3.0, 0.5), 1.0);
+    assert_eq!(smooth_subtraction(-1.0, 3.0, 0.5), -1.0);
+    assert_eq!(smooth_intersection(1.0, 3.0, 0.5), 3.0);
+
+    assert!(smooth_union(1.0, 1.0, 0.5) < 1.0);
+    assert!(smooth_subtraction(1.0, -1.0, 0.5) > 1.0);
+    assert!(smooth_intersection(1.0, 1.0, 0.5) > 1.0);
+  }
+
+  #[test]
+  fn primitives_are_negative_inside() {
+    assert!(sdf_capsule(Vec3::ZERO, 0.5, 1.0) < 0.0);
+    assert!(sdf_torus(Vec3::new(2.0, 0.0, 0.0), 2.0, 0.5) < 0.0);
+    assert!(sdf_torus(Vec3::ZERO, 2.0, 0.5) > 0.0);
+    assert!(sdf_cylinder(Vec3::ZERO, 1.0, 2.0) < 0.0);
+    assert!(sdf_cone(Vec3::ZERO, 1.0, 1.0, 0.0) < 0.0);
+    assert!(sdf_plane(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)) < 0.0);
+  }
+}
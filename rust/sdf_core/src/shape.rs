@@ -0,0 +1,403 @@
+use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
+
+use crate::math::{Vec3, Vec4};
+use crate::scene::{
+  ALL_LAYERS, BLEND_SCENE, FLAG_CAST_SHADOW, FLAG_COLLIDE, FLAG_GRENADE_ONLY, FLAG_PLAYER_ONLY,
+  FLAG_RENDER, FLAG_TRIGGER, LAYER_DEFAULT, OP_INTERSECT, OP_SUBTRACT, OP_UNION, SHAPE_CAPSULE,
+  SHAPE_CONE, SHAPE_CUBE, SHAPE_CYLINDER, SHAPE_PLANE, SHAPE_SPHERE, SHAPE_TORUS,
+};
+
+bitflags! {
+  /// What a shape takes part in, see `QueryFilter` for which queries see
+  /// which shapes. The default collides, renders and casts shadows. Map
+  /// files store them by name, like `"COLLIDE | RENDER"`.
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+  #[serde(transparent)]
+  pub struct ShapeFlags: u32 {
+    const COLLIDE = FLAG_COLLIDE;
+    const RENDER = FLAG_RENDER;
+    const CAST_SHADOW = FLAG_CAST_SHADOW;
+    /// Only seen by trigger queries, never blocks anything.
+    const TRIGGER = FLAG_TRIGGER;
+    /// Only collides with the player.
+    const PLAYER_ONLY = FLAG_PLAYER_ONLY;
+    /// Only collides with grenades.
+    const GRENADE_ONLY = FLAG_GRENADE_ONLY;
+  }
+}
+
+/// The primitive a shape is, with its dimensions in the shape's local space.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShapeKind {
+  Sphere {
+    radius: f32,
+  },
+  Cube {
+    half_extents: Vec3,
+  },
+  /// Along the y axis.
+  Capsule {
+    radius: f32,
+    half_length: f32,
+  },
+  /// In the XZ plane.
+  Torus {
+    major_radius: f32,
+    minor_radius: f32,
+  },
+  /// Along the y axis.
+  Cylinder {
+    radius: f32,
+    half_height: f32,
+  },
+  /// Along the y axis, `bottom_radius` at `-half_height`.
+  Cone {
+    bottom_radius: f32,
+    half_height: f32,
+    top_radius: f32,
+  },
+  /// Through the shape's position.
+  Plane {
+    normal: Vec3,
+  },
+}
+
+/// How a shape combines with the shapes before it, see `Scene`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Operation {
+  #[default]
+  Union,
+  Subtract,
+  Intersect,
+}
+
+/// A shape as callers describe it, `pack` turns it into the rows the scene
+/// stores and uploads to the shaders. Map files only need the kind and
+/// position, every other field defaults to what `new` sets and is left out
+/// when saving while it keeps that value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SdfShape {
+  pub kind: ShapeKind,
+  pub position: Vec3,
+  /// Unit quaternion.
+  #[serde(default = "identity", skip_serializing_if = "is_identity")]
+  pub rotation: Vec4,
+  #[serde(default = "white", skip_serializing_if = "is_white")]
+  pub color: Vec3,
+  /// Id of the shape's material in the scene's `MaterialTable`.
+  #[serde(default, skip_serializing_if = "is_default")]
+  pub material: u32,
+  #[serde(default, skip_serializing_if = "is_default")]
+  pub flags: ShapeFlags,
+  /// Grows the shape outwards with rounded edges.
+  #[serde(default, skip_serializing_if = "is_default")]
+  pub rounding: f32,
+  #[serde(default, skip_serializing_if = "is_default")]
+  pub operation: Operation,
+  /// `None` uses the scene's `blend_factor`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub blend_radius: Option<f32>,
+  /// Bits of the collision layers the shape is on, see `QueryFilter::mask`.
+  #[serde(default = "default_layers", skip_serializing_if = "is_default_layers")]
+  pub collision_layers: u32,
+}
+
+fn identity() -> Vec4 {
+  return Vec4::QUAT_IDENTITY;
+}
+
+fn is_identity(rotation: &Vec4) -> bool {
+  return *rotation == Vec4::QUAT_IDENTITY;
+}
+
+fn white() -> Vec3 {
+  return Vec3::new(1.0, 1.0, 1.0);
+}
+
+fn is_white(color: &Vec3) -> bool {
+  return *color == white();
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+  return *value == T::default();
+}
+
+fn default_layers() -> u32 {
+  return LAYER_DEFAULT;
+}
+
+fn is_default_layers(layers: &u32) -> bool {
+  return *layers == LAYER_DEFAULT;
+}
+
+/// One shape in the layout of the `Scene` shape table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackedShape {
+  pub position: Vec4,
+  pub rotation: Vec4,
+  pub properties: Vec4,
+  pub color: Vec4,
+  pub modifiers: Vec4,
+}
+
+impl ShapeKind {
+  /// `(dimensions.xyz, type)` as stored in the `properties` row.
+  pub fn pack(&self) -> Vec4 {
+    match *self {
+      ShapeKind::Sphere { radius } => return Vec4::new(radius, 0.0, 0.0, SHAPE_SPHERE),
+      ShapeKind::Cube { half_extents } => return half_extents.extend(SHAPE_CUBE),
+      ShapeKind::Capsule {
+        radius,
+        half_length,
+      } => return Vec4::new(radius, half_length, 0.0, SHAPE_CAPSULE),
+      ShapeKind::Torus {
+        major_radius,
+        minor_radius,
+      } => return Vec4::new(major_radius, minor_radius, 0.0, SHAPE_TORUS),
+      ShapeKind::Cylinder {
+        radius,
+        half_height,
+      } => return Vec4::new(radius, half_height, 0.0, SHAPE_CYLINDER),
+      ShapeKind::Cone {
+        bottom_radius,
+        half_height,
+        top_radius,
+      } => return Vec4::new(bottom_radius, half_height, top_radius, SHAPE_CONE),
+      ShapeKind::Plane { normal } => return normal.extend(SHAPE_PLANE),
+    }
+  }
+
+  /// `None` for `SHAPE_NONE` and unknown types.
+  pub fn unpack(properties: Vec4) -> Option<Self> {
+    let size = properties.xyz();
+    if properties.w == SHAPE_SPHERE {
+      return Some(ShapeKind::Sphere { radius: size.x });
+    }
+    if properties.w == SHAPE_CUBE {
+      return Some(ShapeKind::Cube { half_extents: size });
+    }
+    if properties.w == SHAPE_CAPSULE {
+      return Some(ShapeKind::Capsule {
+        radius: size.x,
+        half_length: size.y,
+      });
+    }
+    if properties.w == SHAPE_TORUS {
+      return Some(ShapeKind::Torus {
+        major_radius: size.x,
+        minor_radius: size.y,
+      });
+    }
+    if properties.w == SHAPE_CYLINDER {
+      return Some(ShapeKind::Cylinder {
+        radius: size.x,
+        half_height: size.y,
+      });
+    }
+    if properties.w == SHAPE_CONE {
+      return Some(ShapeKind::Cone {
+        bottom_radius: size.x,
+        half_height: size.y,
+        top_radius: size.z,
+      });
+    }
+    if properties.w == SHAPE_PLANE {
+      return Some(ShapeKind::Plane { normal: size });
+    }
+    return None;
+  }
+}
+
+impl Default for ShapeFlags {
+  fn default() -> Self {
+    return ShapeFlags::COLLIDE | ShapeFlags::RENDER | ShapeFlags::CAST_SHADOW;
+  }
+}
+
+impl ShapeFlags {
+  /// The bits as the float stored in `position.w`, exact for every flag.
+  pub fn pack(self) -> f32 {
+    return self.bits() as f32;
+  }
+
+  /// Unknown bits are dropped.
+  pub fn unpack(flags: f32) -> Self {
+    return ShapeFlags::from_bits_truncate(flags as u32);
+  }
+}
+
+impl Operation {
+  pub fn pack(self) -> f32 {
+    match self {
+      Operation::Union => return OP_UNION,
+      Operation::Subtract => return OP_SUBTRACT,
+      Operation::Intersect => return OP_INTERSECT,
+    }
+  }
+
+  pub fn unpack(operation: f32) -> Option<Self> {
+    if operation == OP_UNION {
+      return Some(Operation::Union);
+    }
+    if operation == OP_SUBTRACT {
+      return Some(Operation::Subtract);
+    }
+    if operation == OP_INTERSECT {
+      return Some(Operation::Intersect);
+    }
+    return None;
+  }
+}
+
+impl SdfShape {
+  /// An unrotated white shape with the default material, flags and collision
+  /// layer, unioned with the scene's blend.
+  pub fn new(kind: ShapeKind, position: Vec3) -> Self {
+    return Self {
+      kind,
+      position,
+      rotation: identity(),
+      color: white(),
+      material: 0,
+      flags: ShapeFlags::default(),
+      rounding: 0.0,
+      operation: Operation::Union,
+      blend_radius: None,
+      collision_layers: LAYER_DEFAULT,
+    };
+  }
+
+  pub fn with_rotation(mut self, rotation: Vec4) -> Self {
+    self.rotation = rotation;
+    return self;
+  }
+
+  pub fn with_color(mut self, color: Vec3) -> Self {
+    self.color = color;
+    return self;
+  }
+
+  pub fn with_material(mut self, material: u32) -> Self {
+    self.material = material;
+    return self;
+  }
+
+  pub fn with_flags(mut self, flags: ShapeFlags) -> Self {
+    self.flags = flags;
+    return self;
+  }
+
+  pub fn with_rounding(mut self, rounding: f32) -> Self {
+    self.rounding = rounding;
+    return self;
+  }
+
+  pub fn with_operation(mut self, operation: Operation, blend_radius: Option<f32>) -> Self {
+    self.operation = operation;
+    self.blend_radius = blend_radius;
+    return self;
+  }
+
+  /// Only the first 24 layers are kept, see `ALL_LAYERS`.
+  pub fn with_collision_layers(mut self, layers: u32) -> Self {
+    self.collision_layers = layers & ALL_LAYERS;
+    return self;
+  }
+
+  pub fn pack(&self) -> PackedShape {
+    return PackedShape {
+      position: self.position.extend(self.flags.pack()),
+      rotation: self.rotation,
+      properties: self.kind.pack(),
+      color: self.color.extend(self.material as f32),
+      modifiers: Vec4::new(
+        self.rounding,
+        self.operation.pack(),
+        self.blend_radius.unwrap_or(BLEND_SCENE),
+        self.collision_layers as f32,
+      ),
+    };
+  }
+
+  /// `None` when the rows hold no known shape type or operation.
+  pub fn unpack(packed: &PackedShape) -> Option<Self> {
+    let blend_radius = packed.modifiers.z;
+    return Some(Self {
+      kind: ShapeKind::unpack(packed.properties)?,
+      position: packed.position.xyz(),
+      rotation: packed.rotation,
+      color: packed.color.xyz(),
+      material: packed.color.w as u32,
+      flags: ShapeFlags::unpack(packed.position.w),
+      rounding: packed.modifiers.x,
+      operation: Operation::unpack(packed.modifiers.y)?,
+      blend_radius: if blend_radius < 0.0 {
+        None
+      } else {
+        Some(blend_radius)
+      },
+      collision_layers: packed.modifiers.w as u32,
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::scene::FLAGS_SOLID;
+
+  #[test]
+  fn every_kind_round_trips_through_the_packing() {
+    let kinds = [
+      ShapeKind::Sphere { radius: 1.0 },
+      ShapeKind::Cube {
+        half_extents: Vec3::new(1.0, 2.0, 3.0),
+      },
+      ShapeKind::Capsule {
+        radius: 0.5,
+        half_length: 1.0,
+      },
+      ShapeKind::Torus {
+        major_radius: 2.0,
+        minor_radius: 0.5,
+      },
+      ShapeKind::Cylinder {
+        radius: 1.0,
+        half_height: 2.0,
+      },
+      ShapeKind::Cone {
+        bottom_radius: 1.0,
+        half_height: 1.0,
+        top_radius: 0.25,
+      },
+      ShapeKind::Plane {
+        normal: Vec3::new(0.0, 1.0, 0.0),
+      },
+    ];
+    for kind in kinds {
+      let shape = SdfShape::new(kind, Vec3::new(1.0, 2.0, 3.0))
+        .with_rotation(Vec4::quat_from_euler(Vec3::new(0.0, 90.0, 0.0)))
+        .with_color(Vec3::new(1.0, 0.0, 1.0))
+        .with_material(3)
+        .with_flags(ShapeFlags::RENDER | ShapeFlags::TRIGGER)
+        .with_rounding(0.1)
+        .with_operation(Operation::Subtract, Some(0.5))
+        .with_collision_layers(0b101);
+      assert_eq!(SdfShape::unpack(&shape.pack()), Some(shape));
+    }
+  }
+
+  #[test]
+  fn flags_combine_in_the_position_flag() {
+    assert_eq!(ShapeFlags::default().pack(), FLAGS_SOLID);
+    let flags = ShapeFlags::RENDER | ShapeFlags::TRIGGER | ShapeFlags::GRENADE_ONLY;
+    assert_eq!(ShapeFlags::unpack(flags.pack()), flags);
+    assert_eq!(
+      ShapeFlags::unpack(ShapeFlags::all().pack()),
+      ShapeFlags::all()
+    );
+    assert_eq!(ShapeFlags::unpack(1024.0), ShapeFlags::empty());
+  }
+}
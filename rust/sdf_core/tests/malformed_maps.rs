@@ -0,0 +1,38 @@
+//! Every map in `malformed_maps` must fail to load with the error its first
+//! line describes, after the `# `.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdf_core::{map, Scene};
+
+fn malformed_maps() -> Vec<PathBuf> {
+  let mut paths: Vec<PathBuf> =
+    fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/malformed_maps"))
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+      .collect();
+  paths.sort();
+  return paths;
+}
+
+#[test]
+fn malformed_maps_report_where_they_fail() {
+  let paths = malformed_maps();
+  assert!(!paths.is_empty());
+
+  for path in paths {
+    let content = fs::read_to_string(&path).unwrap();
+    let expected = content
+      .lines()
+      .next()
+      .and_then(|line| line.strip_prefix("# "))
+      .unwrap_or_else(|| panic!("{} does not describe its error", path.display()));
+
+    let mut scene = Scene::default();
+    let error =
+      map::load_map(&mut scene, &content).expect_err(&format!("{} loaded", path.display()));
+    assert_eq!(error.to_string(), expected, "{}", path.display());
+    assert_eq!(scene.num_shapes(), 0, "{}", path.display());
+  }
+}
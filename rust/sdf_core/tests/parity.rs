@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdf_core::parity::{compare_events, sample_points, sample_sweeps, Tolerance};
+use sdf_core::query::{compute_collision, compute_shapecast, SURF_DIST};
+use sdf_core::{map_file, Scene};
+
+const NUM_POINTS: usize = 4096;
+const NUM_SWEEPS: usize = 256;
+const POINTS_PER_SWEEP: usize = 19;
+
+fn godot_dir() -> PathBuf {
+  return Path::new(env!("CARGO_MANIFEST_DIR")).join("../../godot");
+}
+
+/// The fixtures without caches, so every query evaluates every shape until
+/// `Scene::update_caches` builds the grid.
+fn fixture_maps() -> Vec<(PathBuf, Scene)> {
+  let mut paths = vec![godot_dir().join("default_map.ron")];
+  let mut fixtures: Vec<PathBuf> = fs::read_dir(godot_dir().join("fixtures"))
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+    .collect();
+  fixtures.sort();
+  paths.extend(fixtures);
+
+  return paths
+    .into_iter()
+    .map(|path| {
+      let mut scene = Scene::new(0.5);
+      let content = fs::read_to_string(&path).unwrap();
+      map_file::load(&mut scene, &path.to_string_lossy(), &content).unwrap();
+      (path, scene)
+    })
+    .collect();
+}
+
+#[test]
+fn fixtures_are_not_empty() {
+  for (path, scene) in fixture_maps() {
+    assert!(scene.num_shapes() > 0, "{} has no shapes", path.display());
+  }
+}
+
+#[test]
+fn grid_collision_matches_exhaustive_evaluation() {
+  for (path, exhaustive) in fixture_maps() {
+    let mut scene = exhaustive.clone();
+    scene.update_caches();
+    let reach = scene
+      .grid()
+      .unwrap_or_else(|| panic!("{} has no grid", path.display()))
+      .reach();
+
+    // the grid clamps distances to its reach, normals need some room below it
+    let points: Vec<_> = sample_points(&scene, NUM_POINTS, 1)
+      .into_iter()
+      .filter(|point| exhaustive.get_scene_dist(point.xyz()) < reach * 0.5)
+      .collect();
+    assert!(
+      !points.is_empty(),
+      "{}: no points near surfaces",
+      path.display()
+    );
+
+    let expected = compute_collision(&exhaustive, &points);
+    let events = compute_collision(&scene, &points);
+    let mismatches = compare_events(&expected, &events, Tolerance::default());
+    assert!(
+      mismatches.is_empty(),
+      "{}: {:?}",
+      path.display(),
+      mismatches
+    );
+  }
+}
+
+#[test]
+fn shapecast_hits_agree_with_collision() {
+  for (path, mut scene) in fixture_maps() {
+    scene.update_caches();
+    let mut hits = 0;
+    let mut surface_hits = 0;
+
+    for sweep in sample_sweeps(&scene, NUM_SWEEPS, POINTS_PER_SWEEP, 2.0, 2) {
+      let events = compute_shapecast(&scene, &sweep.points, sweep.velocity);
+      assert_eq!(events.len(), sweep.points.len());
+
+      let direction = sweep.velocity.xyz().normalized();
+      let speed = sweep.velocity.xyz().length();
+      for (point, event) in sweep.points.iter().zip(&events) {
+        assert!(
+          event.w <= 1.0,
+          "{}: fraction {} out of range",
+          path.display(),
+          event.w
+        );
+
+        let origin = point.xyz() + direction * SURF_DIST * 10.0;
+        if event.w >= 1.0 || scene.get_scene_dist(origin) < SURF_DIST {
+          continue;
+        }
+
+        // a sweep that starts outside the scene must never end up inside it
+        let hit = origin + direction * (event.w * speed);
+        let hit_dist = scene.get_scene_dist(hit);
+        assert!(
+          hit_dist > -SURF_DIST,
+          "{}: sweep from {:?} tunnelled {} into the surface",
+          path.display(),
+          point,
+          -hit_dist
+        );
+
+        hits += 1;
+        if hit_dist < SURF_DIST {
+          surface_hits += 1;
+        }
+      }
+    }
+
+    // grazing rays can run out of steps before reaching the surface
+    assert!(
+      surface_hits * 100 >= hits * 95,
+      "{}: only {} of {} hits reached the surface",
+      path.display(),
+      surface_hits,
+      hits
+    );
+  }
+}
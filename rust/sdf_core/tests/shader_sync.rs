@@ -0,0 +1,110 @@
+//! The SDF code is duplicated between the compute shaders and the renderer,
+//! these tests fail as soon as one copy is edited without the others.
+use std::fs;
+use std::path::Path;
+
+fn read_shader(name: &str) -> String {
+  let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("../../godot")
+    .join(name);
+  return fs::read_to_string(path).unwrap();
+}
+
+fn section<'a>(source: &'a str, start: &str, end: &str) -> Vec<&'a str> {
+  let start_index = source
+    .find(start)
+    .unwrap_or_else(|| panic!("missing {}", start));
+  let end_index = source[start_index..]
+    .find(end)
+    .unwrap_or_else(|| panic!("missing {}", end));
+
+  return source[start_index..start_index + end_index]
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .collect();
+}
+
+/// Every function defined in `lines`, signature through closing brace, so
+/// whole definitions are compared rather than loose lines.
+fn functions<'a>(lines: &[&'a str]) -> Vec<Vec<&'a str>> {
+  let mut functions = Vec::new();
+  let mut current: Vec<&str> = Vec::new();
+  let mut depth = 0;
+  for line in lines {
+    if depth == 0 && !line.ends_with('{') {
+      // comments and declarations between functions
+      continue;
+    }
+    current.push(line);
+    depth += line.matches('{').count();
+    depth -= line.matches('}').count();
+    if depth == 0 {
+      functions.push(std::mem::take(&mut current));
+    }
+  }
+  assert!(current.is_empty(), "unbalanced braces in `{:?}`", current);
+  return functions;
+}
+
+#[test]
+fn primitives_match_across_shaders() {
+  let collision = read_shader("collision.glsl");
+  let shapecast = read_shader("shapecast.glsl");
+  let renderer = read_shader("sdf_renderer.gdshader");
+
+  let start = "// <SDF Primitives>";
+  let end = "// <\\SDF Primitives>";
+  let expected = section(&collision, start, end);
+  assert_eq!(section(&shapecast, start, end), expected);
+  assert_eq!(section(&renderer, start, end), expected);
+}
+
+#[test]
+fn scene_distance_matches_between_compute_shaders() {
+  let collision = read_shader("collision.glsl");
+  let shapecast = read_shader("shapecast.glsl");
+
+  let start = "// <SDF Operations>";
+  let end = "void main()";
+  assert_eq!(
+    section(&shapecast, start, end),
+    section(&collision, start, end)
+  );
+}
+
+#[test]
+fn scene_evaluation_matches_across_shaders() {
+  let collision = read_shader("collision.glsl");
+  let shapecast = read_shader("shapecast.glsl");
+  let renderer = read_shader("sdf_renderer.gdshader");
+
+  // each shader reads the shapes through its own accessors and `SCENE_INFO`,
+  // which is the distance or, in the renderer, the colour and distance
+  let start = "// <SDF Scene>";
+  let end = "// <\\SDF Scene>";
+  let expected = section(&collision, start, end);
+  assert!(!expected.is_empty());
+  assert_eq!(section(&shapecast, start, end), expected);
+  assert_eq!(section(&renderer, start, end), expected);
+}
+
+#[test]
+fn renderer_operations_contain_compute_operations() {
+  let collision = read_shader("collision.glsl");
+  let renderer = read_shader("sdf_renderer.gdshader");
+
+  // the renderer adds colored overloads next to the compute shaders' ones
+  let start = "// <SDF Operations>";
+  let end = "// <\\SDF Operations>";
+  let renderer_functions = functions(&section(&renderer, start, end));
+  let collision_functions = functions(&section(&collision, start, end));
+  assert!(!collision_functions.is_empty());
+  for function in collision_functions {
+    assert!(
+      renderer_functions.contains(&function),
+      "renderer is missing or has changed `{}`",
+      function[0]
+    );
+  }
+}
@@ -0,0 +1,668 @@
+use godot::classes::rendering_device::UniformType;
+use godot::classes::{RdShaderFile, RdUniform, RenderingDevice, RenderingServer};
+use godot::prelude::*;
+use sdf_core::grid::ShapeGrid;
+use sdf_core::query::{self, Query, QueryFilter};
+use sdf_core::{Scene, Vec4};
+
+const COLLISION_SHADER_PATH: &str = "res://collision.glsl";
+const SHAPECAST_SHADER_PATH: &str = "res://shapecast.glsl";
+
+/// Which implementation `SdfController` uses to answer collision queries.
+/// `Auto` picks the GPU when a local rendering device is available.
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[godot(via = GString)]
+pub enum CollisionBackendKind {
+  #[default]
+  Auto,
+  Gpu,
+  Cpu,
+}
+
+/// Answers distance and sweep queries against the current scene. Both
+/// implementations return one `(normal.xyz, distance/fraction)` per point.
+pub trait CollisionBackend {
+  fn compute_collision(&mut self, scene: &Scene, points: PackedVector4Array) -> Vec<Vector4>;
+
+  /// Resolves every query in one pass, results are in the same order. Each
+  /// query only sees the shapes its entry in `filters` accepts.
+  fn compute_queries(
+    &mut self,
+    scene: &Scene,
+    queries: &[Query],
+    filters: &[QueryFilter],
+  ) -> Vec<Vector4>;
+
+  /// Starts resolving `queries` without waiting for the results, which are
+  /// picked up by the next call to `collect_queries`. Submitting again before
+  /// collecting replaces the earlier submission.
+  fn submit_queries(&mut self, scene: &Scene, queries: &[Query], filters: &[QueryFilter]);
+
+  /// Results of the last `submit_queries`, or `None` if nothing was submitted
+  /// since the last collect.
+  fn collect_queries(&mut self) -> Option<Vec<Vector4>>;
+
+  fn compute_shapecast(
+    &mut self,
+    scene: &Scene,
+    points: PackedVector4Array,
+    velocity: Vector4,
+  ) -> Vec<Vector4> {
+    let queries: Vec<Query> = points
+      .as_slice()
+      .iter()
+      .map(|point| Query::SweptPoint {
+        origin: from_vector4(*point).xyz(),
+        velocity: from_vector4(velocity).xyz(),
+      })
+      .collect();
+    return self.compute_queries(scene, &queries, &vec![QueryFilter::SOLID; queries.len()]);
+  }
+}
+
+pub fn create_backend(kind: CollisionBackendKind, max_shapes: usize) -> Box<dyn CollisionBackend> {
+  if kind == CollisionBackendKind::Cpu {
+    return Box::new(CpuBackend::default());
+  }
+
+  match GpuBackend::new(max_shapes) {
+    Some(backend) => return Box::new(backend),
+    None => {
+      if kind == CollisionBackendKind::Gpu {
+        godot_warn!("No local rendering device available, falling back to CPU collision");
+      }
+      return Box::new(CpuBackend::default());
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct CpuBackend {
+  collected: Option<Vec<Vector4>>,
+}
+
+impl CollisionBackend for CpuBackend {
+  fn compute_collision(&mut self, scene: &Scene, points: PackedVector4Array) -> Vec<Vector4> {
+    let points = from_packed_array(&points);
+    return query::compute_collision(scene, &points)
+      .into_iter()
+      .map(to_vector4)
+      .collect();
+  }
+
+  fn compute_queries(
+    &mut self,
+    scene: &Scene,
+    queries: &[Query],
+    filters: &[QueryFilter],
+  ) -> Vec<Vector4> {
+    return query::compute_queries(scene, queries, filters)
+      .into_iter()
+      .map(to_vector4)
+      .collect();
+  }
+
+  fn submit_queries(&mut self, scene: &Scene, queries: &[Query], filters: &[QueryFilter]) {
+    self.collected = Some(self.compute_queries(scene, queries, filters));
+  }
+
+  fn collect_queries(&mut self) -> Option<Vec<Vector4>> {
+    return self.collected.take();
+  }
+}
+
+const INITIAL_POINT_CAPACITY: usize = 64;
+const INITIAL_GRID_CAPACITY: usize = 4096;
+const VECTOR4_SIZE: usize = 16;
+// one instruction per shape plus a push and a pop per group, and a scene has
+// at most as many groups as shapes
+const INSTRUCTIONS_PER_SHAPE: usize = 3;
+// `local_size_x` of both compute shaders
+const WORKGROUP_SIZE: usize = 64;
+// vectors per query in the shapecast point buffer, see `pack_queries`
+const QUERY_STRIDE: usize = 3;
+
+/// A compute shader with its pipeline, point buffer and uniform set. The
+/// point and grid buffers only grow, so the uniform set is recreated when
+/// either does.
+struct ComputeKernel {
+  shader: Rid,
+  pipeline: Rid,
+  point_buffer: Rid,
+  point_capacity: usize,
+  uniform_set: Rid,
+}
+
+pub struct GpuBackend {
+  rendering_device: Gd<RenderingDevice>,
+
+  position_buffer: Rid,
+  property_buffer: Rid,
+  modifier_buffer: Rid,
+  rotation_buffer: Rid,
+  instruction_buffer: Rid,
+  data_buffer: Rid,
+  grid_buffer: Rid,
+  grid_capacity: usize,
+  collision: ComputeKernel,
+  shapecast: ComputeKernel,
+  async_queries: ComputeKernel,
+
+  pending_queries: Option<usize>,
+  collected: Option<Vec<Vector4>>,
+  uploaded_revision: Option<u64>,
+  uploaded_layout_revision: Option<u64>,
+  uploaded_blend_factor: f32,
+  uploaded_num_shapes: u32,
+  uploaded_num_instructions: u32,
+  uploaded_grid: bool,
+}
+
+impl CollisionBackend for GpuBackend {
+  fn compute_collision(&mut self, scene: &Scene, points: PackedVector4Array) -> Vec<Vector4> {
+    let num_points = points.len();
+    self.sync_pending();
+    self.upload_scene(scene);
+
+    let mut kernel = self.take_kernel(KernelKind::Collision);
+    let events = self.dispatch(&mut kernel, points, num_points, 1);
+    self.collision = kernel;
+    return events;
+  }
+
+  fn compute_queries(
+    &mut self,
+    scene: &Scene,
+    queries: &[Query],
+    filters: &[QueryFilter],
+  ) -> Vec<Vector4> {
+    self.sync_pending();
+    self.upload_scene(scene);
+
+    let mut kernel = self.take_kernel(KernelKind::Shapecast);
+    let events = self.dispatch(
+      &mut kernel,
+      pack_queries(queries, filters),
+      queries.len(),
+      QUERY_STRIDE,
+    );
+    self.shapecast = kernel;
+    return events;
+  }
+
+  fn submit_queries(&mut self, scene: &Scene, queries: &[Query], filters: &[QueryFilter]) {
+    self.sync_pending();
+    self.collected = None;
+    if queries.is_empty() {
+      self.collected = Some(Vec::new());
+      return;
+    }
+    self.upload_scene(scene);
+
+    let mut kernel = self.take_kernel(KernelKind::AsyncQueries);
+    self.record(&mut kernel, pack_queries(queries, filters), queries.len());
+    self.async_queries = kernel;
+
+    self.rendering_device.submit();
+    self.pending_queries = Some(queries.len());
+  }
+
+  fn collect_queries(&mut self) -> Option<Vec<Vector4>> {
+    self.sync_pending();
+    return self.collected.take();
+  }
+}
+
+#[derive(Clone, Copy)]
+enum KernelKind {
+  Collision,
+  Shapecast,
+  AsyncQueries,
+}
+
+impl GpuBackend {
+  /// Shape buffers are sized for `max_shapes`, scenes passed to this backend
+  /// must not hold more than that.
+  pub fn new(max_shapes: usize) -> Option<Self> {
+    let mut rendering_device = RenderingServer::singleton().create_local_rendering_device()?;
+
+    let shape_bytes = (max_shapes.max(1) * VECTOR4_SIZE) as u32;
+    let position_buffer = rendering_device.storage_buffer_create(shape_bytes);
+    let property_buffer = rendering_device.storage_buffer_create(shape_bytes);
+    let modifier_buffer = rendering_device.storage_buffer_create(shape_bytes);
+    let rotation_buffer = rendering_device.storage_buffer_create(shape_bytes);
+    let instruction_buffer =
+      rendering_device.storage_buffer_create(shape_bytes * INSTRUCTIONS_PER_SHAPE as u32);
+    let data_buffer = rendering_device.storage_buffer_create(4);
+    let grid_buffer = rendering_device.storage_buffer_create(INITIAL_GRID_CAPACITY as u32);
+
+    let mut backend = Self {
+      rendering_device,
+      position_buffer,
+      property_buffer,
+      modifier_buffer,
+      rotation_buffer,
+      instruction_buffer,
+      data_buffer,
+      grid_buffer,
+      grid_capacity: INITIAL_GRID_CAPACITY,
+      collision: ComputeKernel::EMPTY,
+      shapecast: ComputeKernel::EMPTY,
+      async_queries: ComputeKernel::EMPTY,
+      pending_queries: None,
+      collected: None,
+      uploaded_revision: None,
+      uploaded_layout_revision: None,
+      uploaded_blend_factor: f32::NAN,
+      uploaded_num_shapes: 0,
+      uploaded_num_instructions: 0,
+      uploaded_grid: false,
+    };
+    backend.collision = backend.create_kernel(COLLISION_SHADER_PATH);
+    backend.shapecast = backend.create_kernel(SHAPECAST_SHADER_PATH);
+    backend.async_queries = backend.create_kernel(SHAPECAST_SHADER_PATH);
+    return Some(backend);
+  }
+
+  fn create_kernel(&mut self, shader_path: &str) -> ComputeKernel {
+    let shader_code = load::<RdShaderFile>(shader_path).get_spirv().unwrap();
+    let shader = self.rendering_device.shader_create_from_spirv(&shader_code);
+    let pipeline = self.rendering_device.compute_pipeline_create(shader);
+
+    let mut kernel = ComputeKernel {
+      shader,
+      pipeline,
+      ..ComputeKernel::EMPTY
+    };
+    self.reserve_points(&mut kernel, INITIAL_POINT_CAPACITY);
+    return kernel;
+  }
+
+  fn take_kernel(&mut self, kind: KernelKind) -> ComputeKernel {
+    let kernel = match kind {
+      KernelKind::Collision => &mut self.collision,
+      KernelKind::Shapecast => &mut self.shapecast,
+      KernelKind::AsyncQueries => &mut self.async_queries,
+    };
+    return std::mem::replace(kernel, ComputeKernel::EMPTY);
+  }
+
+  /// Makes sure the kernel's point buffer can hold `num_points` vectors.
+  fn reserve_points(&mut self, kernel: &mut ComputeKernel, num_points: usize) {
+    if kernel.point_capacity >= num_points {
+      return;
+    }
+
+    if kernel.uniform_set.is_valid() {
+      self.rendering_device.free_rid(kernel.uniform_set);
+    }
+    if kernel.point_buffer.is_valid() {
+      self.rendering_device.free_rid(kernel.point_buffer);
+    }
+
+    kernel.point_capacity = num_points.next_power_of_two();
+    kernel.point_buffer = self
+      .rendering_device
+      .storage_buffer_create((kernel.point_capacity * VECTOR4_SIZE) as u32);
+    self.create_uniform_set(kernel);
+  }
+
+  /// Makes sure the grid buffer can hold `num_bytes`, every kernel is bound to
+  /// the new buffer when it has to grow.
+  fn reserve_grid(&mut self, num_bytes: usize) {
+    if self.grid_capacity >= num_bytes {
+      return;
+    }
+
+    let mut kernels = [
+      self.take_kernel(KernelKind::Collision),
+      self.take_kernel(KernelKind::Shapecast),
+      self.take_kernel(KernelKind::AsyncQueries),
+    ];
+    for kernel in &kernels {
+      self.rendering_device.free_rid(kernel.uniform_set);
+    }
+    self.rendering_device.free_rid(self.grid_buffer);
+
+    self.grid_capacity = num_bytes.next_power_of_two();
+    self.grid_buffer = self
+      .rendering_device
+      .storage_buffer_create(self.grid_capacity as u32);
+    for kernel in &mut kernels {
+      self.create_uniform_set(kernel);
+    }
+
+    let [collision, shapecast, async_queries] = kernels;
+    self.collision = collision;
+    self.shapecast = shapecast;
+    self.async_queries = async_queries;
+  }
+
+  fn create_uniform_set(&mut self, kernel: &mut ComputeKernel) {
+    let mut points_uniform = RdUniform::new_gd();
+    points_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    points_uniform.set_binding(0);
+    points_uniform.add_id(kernel.point_buffer);
+
+    let mut position_uniform = RdUniform::new_gd();
+    position_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    position_uniform.set_binding(1);
+    position_uniform.add_id(self.position_buffer);
+
+    let mut property_uniform = RdUniform::new_gd();
+    property_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    property_uniform.set_binding(2);
+    property_uniform.add_id(self.property_buffer);
+
+    let mut data_uniform = RdUniform::new_gd();
+    data_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    data_uniform.set_binding(3);
+    data_uniform.add_id(self.data_buffer);
+
+    let mut modifier_uniform = RdUniform::new_gd();
+    modifier_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    modifier_uniform.set_binding(5);
+    modifier_uniform.add_id(self.modifier_buffer);
+
+    let mut rotation_uniform = RdUniform::new_gd();
+    rotation_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    rotation_uniform.set_binding(6);
+    rotation_uniform.add_id(self.rotation_buffer);
+
+    let mut instruction_uniform = RdUniform::new_gd();
+    instruction_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    instruction_uniform.set_binding(7);
+    instruction_uniform.add_id(self.instruction_buffer);
+
+    let mut grid_uniform = RdUniform::new_gd();
+    grid_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
+    grid_uniform.set_binding(4);
+    grid_uniform.add_id(self.grid_buffer);
+
+    kernel.uniform_set = self.rendering_device.uniform_set_create(
+      &Array::from(&[
+        points_uniform,
+        position_uniform,
+        property_uniform,
+        data_uniform,
+        grid_uniform,
+        modifier_uniform,
+        rotation_uniform,
+        instruction_uniform,
+      ]),
+      kernel.shader,
+      0,
+    );
+  }
+
+  /// Uploads the shape table only when it changed since the last query. The
+  /// scene's caches have to be up to date, see `Scene::update_caches`.
+  fn upload_scene(&mut self, scene: &Scene) {
+    let shapes_changed = self.uploaded_revision != Some(scene.revision());
+    // shapes moving within their grid cells leave the grid and instructions
+    let layout_changed = self.uploaded_layout_revision != Some(scene.layout_revision());
+    let blend_factor_changed = self.uploaded_blend_factor != scene.blend_factor;
+    let grid = scene.grid();
+    if layout_changed || blend_factor_changed || grid.is_some() != self.uploaded_grid {
+      self.upload_grid(grid);
+    }
+
+    // only the live shapes are uploaded, the shaders never read past them
+    if shapes_changed && scene.num_shapes() > 0 {
+      let position_bytes = to_packed_array(scene.positions()).to_byte_array();
+      let property_bytes = to_packed_array(scene.properties()).to_byte_array();
+      let modifier_bytes = to_packed_array(scene.modifiers()).to_byte_array();
+      let rotation_bytes = to_packed_array(scene.rotations()).to_byte_array();
+
+      self.rendering_device.buffer_update(
+        self.position_buffer,
+        0,
+        position_bytes.len() as u32,
+        &position_bytes,
+      );
+      self.rendering_device.buffer_update(
+        self.property_buffer,
+        0,
+        property_bytes.len() as u32,
+        &property_bytes,
+      );
+      self.rendering_device.buffer_update(
+        self.modifier_buffer,
+        0,
+        modifier_bytes.len() as u32,
+        &modifier_bytes,
+      );
+      self.rendering_device.buffer_update(
+        self.rotation_buffer,
+        0,
+        rotation_bytes.len() as u32,
+        &rotation_bytes,
+      );
+    }
+    if layout_changed {
+      let instructions = scene.instructions().unwrap_or_default();
+      if !instructions.is_empty() {
+        let instruction_bytes = to_packed_array(instructions).to_byte_array();
+        self.rendering_device.buffer_update(
+          self.instruction_buffer,
+          0,
+          instruction_bytes.len() as u32,
+          &instruction_bytes,
+        );
+      }
+      self.uploaded_num_instructions = instructions.len() as u32;
+    }
+    self.uploaded_revision = Some(scene.revision());
+    self.uploaded_layout_revision = Some(scene.layout_revision());
+    self.uploaded_num_shapes = scene.num_shapes() as u32;
+
+    if blend_factor_changed {
+      let data_bytes = PackedArray::from([scene.blend_factor]).to_byte_array();
+      self.rendering_device.buffer_update(
+        self.data_buffer,
+        0,
+        data_bytes.len() as u32,
+        &data_bytes,
+      );
+      self.uploaded_blend_factor = scene.blend_factor;
+    }
+  }
+
+  /// Without a current grid the header is zeroed, which makes the shaders
+  /// blend every shape.
+  fn upload_grid(&mut self, grid: Option<&ShapeGrid>) {
+    let header = grid.map_or([Vec4::ZERO; 2], |grid| grid.header());
+    let mut grid_bytes = to_packed_array(&header).to_byte_array();
+    if let Some(grid) = grid {
+      let cells: PackedInt32Array = grid.cells().iter().map(|cell| *cell as i32).collect();
+      grid_bytes.extend_array(&cells.to_byte_array());
+    }
+
+    self.reserve_grid(grid_bytes.len());
+    self
+      .rendering_device
+      .buffer_update(self.grid_buffer, 0, grid_bytes.len() as u32, &grid_bytes);
+    self.uploaded_grid = grid.is_some();
+  }
+
+  /// Runs one invocation per `stride` vectors of `points` and reads back the
+  /// first vector of each.
+  fn dispatch(
+    &mut self,
+    kernel: &mut ComputeKernel,
+    points: PackedVector4Array,
+    num_points: usize,
+    stride: usize,
+  ) -> Vec<Vector4> {
+    if num_points == 0 {
+      return Vec::new();
+    }
+
+    self.record(kernel, points, num_points);
+    self.rendering_device.submit();
+    self.rendering_device.sync();
+    return self.read_back(kernel, num_points, stride);
+  }
+
+  /// Uploads `points` and records the compute list, without submitting it.
+  fn record(&mut self, kernel: &mut ComputeKernel, points: PackedVector4Array, num_points: usize) {
+    self.reserve_points(kernel, points.len());
+
+    let point_bytes = points.to_byte_array();
+    self.rendering_device.buffer_update(
+      kernel.point_buffer,
+      0,
+      point_bytes.len() as u32,
+      &point_bytes,
+    );
+
+    let compute_list = self.rendering_device.compute_list_begin();
+    self
+      .rendering_device
+      .compute_list_bind_compute_pipeline(compute_list, kernel.pipeline);
+    self
+      .rendering_device
+      .compute_list_bind_uniform_set(compute_list, kernel.uniform_set, 0);
+
+    // matches the `Params` push constant, padded to 16 bytes
+    let params = PackedInt32Array::from([
+      num_points as i32,
+      self.uploaded_num_shapes as i32,
+      self.uploaded_num_instructions as i32,
+      0,
+    ])
+    .to_byte_array();
+    self.rendering_device.compute_list_set_push_constant(
+      compute_list,
+      &params,
+      params.len() as u32,
+    );
+
+    let num_workgroups = num_points.div_ceil(WORKGROUP_SIZE);
+    self
+      .rendering_device
+      .compute_list_dispatch(compute_list, num_workgroups as u32, 1, 1);
+    self.rendering_device.compute_list_end();
+  }
+
+  fn read_back(
+    &mut self,
+    kernel: &ComputeKernel,
+    num_points: usize,
+    stride: usize,
+  ) -> Vec<Vector4> {
+    let output_bytes = self
+      .rendering_device
+      .buffer_get_data_ex(kernel.point_buffer)
+      .size_bytes((num_points * stride * VECTOR4_SIZE) as u32)
+      .done();
+    let output = output_bytes.to_float32_array();
+
+    let mut events = Vec::new();
+    for i in 0..num_points {
+      let offset = i * stride * 4;
+      events.push(Vector4::new(
+        output[offset + 0],
+        output[offset + 1],
+        output[offset + 2],
+        output[offset + 3],
+      ));
+    }
+    return events;
+  }
+
+  /// Waits for queries submitted by `submit_queries` and keeps their results
+  /// until `collect_queries` is called.
+  fn sync_pending(&mut self) {
+    let Some(num_queries) = self.pending_queries.take() else {
+      return;
+    };
+
+    self.rendering_device.sync();
+    let kernel = self.take_kernel(KernelKind::AsyncQueries);
+    self.collected = Some(self.read_back(&kernel, num_queries, QUERY_STRIDE));
+    self.async_queries = kernel;
+  }
+
+  fn free_kernel(&mut self, kernel: ComputeKernel) {
+    for rid in [
+      kernel.uniform_set,
+      kernel.point_buffer,
+      kernel.pipeline,
+      kernel.shader,
+    ] {
+      if rid.is_valid() {
+        self.rendering_device.free_rid(rid);
+      }
+    }
+  }
+}
+
+impl ComputeKernel {
+  const EMPTY: ComputeKernel = ComputeKernel {
+    shader: Rid::Invalid,
+    pipeline: Rid::Invalid,
+    point_buffer: Rid::Invalid,
+    point_capacity: 0,
+    uniform_set: Rid::Invalid,
+  };
+}
+
+impl Drop for GpuBackend {
+  fn drop(&mut self) {
+    // the device has to be idle before anything it is using can be freed
+    self.sync_pending();
+
+    let async_queries = self.take_kernel(KernelKind::AsyncQueries);
+    self.free_kernel(async_queries);
+    let collision = self.take_kernel(KernelKind::Collision);
+    let shapecast = self.take_kernel(KernelKind::Shapecast);
+    self.free_kernel(collision);
+    self.free_kernel(shapecast);
+
+    self.rendering_device.free_rid(self.position_buffer);
+    self.rendering_device.free_rid(self.property_buffer);
+    self.rendering_device.free_rid(self.modifier_buffer);
+    self.rendering_device.free_rid(self.rotation_buffer);
+    self.rendering_device.free_rid(self.instruction_buffer);
+    self.rendering_device.free_rid(self.data_buffer);
+    self.rendering_device.free_rid(self.grid_buffer);
+    self.rendering_device.clone().free();
+  }
+}
+
+/// Every query's `Query::pack` followed by its filter's `QueryFilter::pack`.
+fn pack_queries(queries: &[Query], filters: &[QueryFilter]) -> PackedVector4Array {
+  let mut packed = PackedVector4Array::new();
+  for (query, filter) in queries.iter().zip(filters) {
+    for vector in query.pack() {
+      packed.push(to_vector4(vector));
+    }
+    packed.push(to_vector4(filter.pack()));
+  }
+  return packed;
+}
+
+pub fn from_vector4(vector: Vector4) -> Vec4 {
+  return Vec4::new(vector.x, vector.y, vector.z, vector.w);
+}
+
+pub fn from_quaternion(rotation: Quaternion) -> Vec4 {
+  return Vec4::new(rotation.x, rotation.y, rotation.z, rotation.w);
+}
+
+pub fn to_vector4(vector: Vec4) -> Vector4 {
+  return Vector4::new(vector.x, vector.y, vector.z, vector.w);
+}
+
+pub fn from_packed_array(vectors: &PackedVector4Array) -> Vec<Vec4> {
+  return vectors
+    .as_slice()
+    .iter()
+    .map(|vector| from_vector4(*vector))
+    .collect();
+}
+
+pub fn to_packed_array(vectors: &[Vec4]) -> PackedVector4Array {
+  return vectors.iter().map(|vector| to_vector4(*vector)).collect();
+}
@@ -1,13 +1,63 @@
 use godot::prelude::*;
+use sdf_core::query::{Query, QueryBatch, QueryFilter, QueryHandle, QueryResults};
+use sdf_core::scene::ALL_LAYERS;
+use sdf_core::{Material, Operation, SdfShape, ShapeHandle, Vec3};
 
 use crate::{
   grenade::{self, Grenade},
   player::Player,
-  sdf_controller::{self, SdfController},
+  sdf_controller::SdfController,
+  shape_handle,
 };
 
 const GRENADE_SPEED: f32 = 5.0;
 
+/// Handles of the queries submitted asynchronously last frame, grenades are
+/// keyed by shape handle since the list can change before they resolve.
+#[derive(Default)]
+struct PendingQueries {
+  player: Option<PlayerQueries>,
+  grenades: Vec<(ShapeHandle, QueryHandle)>,
+}
+
+/// The player's swept points along with where they were swept from, so the
+/// hit point matches the sweep even when it resolves a frame late.
+struct PlayerQueries {
+  handles: Vec<QueryHandle>,
+  origins: Vec<Vec3>,
+  velocity: Vec3,
+}
+
+impl PlayerQueries {
+  fn push(batch: &mut QueryBatch, origins: &[Vec3], velocity: Vec3, filter: QueryFilter) -> Self {
+    let handles = origins
+      .iter()
+      .map(|origin| {
+        let query = Query::SweptPoint {
+          origin: *origin,
+          velocity,
+        };
+        batch.push_filtered(query, filter)
+      })
+      .collect();
+    return Self {
+      handles,
+      origins: origins.to_vec(),
+      velocity,
+    };
+  }
+
+  /// The earliest hit among the swept points and where it happened.
+  fn collision(&self, results: &QueryResults<Vector4>) -> (Vector4, Option<Vector3>) {
+    let (collision, index) = player_collision(results, &self.handles);
+    let hit_point = index.map(|index| {
+      let hit = self.origins[index] + self.velocity * collision.w;
+      Vector3::new(hit.x, hit.y, hit.z)
+    });
+    return (collision, hit_point);
+  }
+}
+
 #[derive(GodotClass)]
 #[class(base = Node3D)]
 pub struct GameController {
@@ -15,7 +65,22 @@ pub struct GameController {
   base: Base<Node3D>,
 
   grenade_scene: Gd<PackedScene>,
-  grenades: Vec<(usize, Gd<Grenade>)>,
+  grenades: Vec<(ShapeHandle, Gd<Grenade>)>,
+  pending_queries: PendingQueries,
+
+  /// Resolve grenade collisions asynchronously, a frame late, instead of
+  /// waiting on the GPU.
+  #[export]
+  latency_tolerant_grenades: bool,
+  /// Grenades carve holes through the level instead of rendering as spheres.
+  #[export]
+  carve_grenades: bool,
+  /// Collision layers of the shapes the player collides with.
+  #[export(flags_3d_physics)]
+  player_collision_mask: u32,
+  /// Collision layers of the shapes grenades collide with.
+  #[export(flags_3d_physics)]
+  grenade_collision_mask: u32,
 
   #[export]
   player: Option<Gd<Player>>,
@@ -30,6 +95,11 @@ impl INode3D for GameController {
       base,
       grenade_scene: load::<PackedScene>("res://grenade.tscn"),
       grenades: Vec::new(),
+      pending_queries: PendingQueries::default(),
+      latency_tolerant_grenades: true,
+      carve_grenades: false,
+      player_collision_mask: ALL_LAYERS,
+      grenade_collision_mask: ALL_LAYERS,
 
       player: None,
       sdf_controller: None,
@@ -46,80 +116,172 @@ impl INode3D for GameController {
       .signals()
       .remove_grenade()
       .connect_self(Self::on_remove_grenade);
+
+    // the first map was loaded when the controller, a child, became ready
+    let spawn = self.sdf_controller().bind().player_spawn();
+    self
+      .player()
+      .bind_mut()
+      .respawn(spawn.unwrap_or(Vector3::ZERO));
+
+    let game_controller = self.to_gd();
+    self
+      .sdf_controller()
+      .signals()
+      .map_loaded()
+      .connect_other(&game_controller, Self::on_map_loaded);
   }
 
   fn physics_process(&mut self, dt: f64) {
     let player = self.player();
     let mut sdf_controller = self.sdf_controller();
 
-    let player_collider = player.bind().get_points();
+    // results of the latency tolerant queries submitted last frame
+    let previous_results = sdf_controller.bind_mut().collect_queries();
+    let previous_queries = std::mem::take(&mut self.pending_queries);
+
     let player_velocity = {
-      let vel = player.bind().get_velocity();
-      Vector4::new(vel.x, vel.y, vel.z, 0.0) * dt as f32
+      let vel = player.bind().get_velocity() * dt as f32;
+      Vec3::new(vel.x, vel.y, vel.z)
     };
+    let player_latency_tolerant = player.bind().latency_tolerant_shapecast;
+    let player_filter = QueryFilter::PLAYER.with_mask(self.player_collision_mask);
+    let grenade_filter = QueryFilter::GRENADE.with_mask(self.grenade_collision_mask);
 
-    let shapecast_events = self
-      .sdf_controller()
-      .bind_mut()
-      .compute_shapecast(player_collider, player_velocity);
-
-    let mut lowest_dist = 1.0;
-    let mut collision = Vector4::new(0.0, 0.0, 0.0, 1.0);
-    for i in 0..shapecast_events.len() {
-      if shapecast_events[i].length() != 0.0 && shapecast_events[i].w < lowest_dist {
-        lowest_dist = collision.w;
-        collision = shapecast_events[i];
+    let mut queries = QueryBatch::new();
+    let mut async_queries = QueryBatch::new();
+
+    let player_points: Vec<Vec3> = player
+      .bind()
+      .get_points()
+      .as_slice()
+      .iter()
+      .map(|point| Vec3::new(point.x, point.y, point.z))
+      .collect();
+    let previous_player = match (previous_queries.player, &previous_results) {
+      (Some(sweep), Some(results)) if player_latency_tolerant => Some((sweep, results)),
+      _ => None,
+    };
+    // without a sweep from last frame to resolve, on the first frame or right
+    // after switching to latency tolerance, the player is swept now so it
+    // cannot pass through the level
+    let current_player = match previous_player {
+      Some(_) => None,
+      None => Some(PlayerQueries::push(
+        &mut queries,
+        &player_points,
+        player_velocity,
+        player_filter,
+      )),
+    };
+    let next_player = if player_latency_tolerant {
+      Some(PlayerQueries::push(
+        &mut async_queries,
+        &player_points,
+        player_velocity,
+        player_filter,
+      ))
+    } else {
+      None
+    };
+
+    let grenade_batch = if self.latency_tolerant_grenades {
+      &mut async_queries
+    } else {
+      &mut queries
+    };
+    let grenade_queries: Vec<(ShapeHandle, QueryHandle)> = self
+      .grenades
+      .iter()
+      .map(|(shape, grenade)| {
+        let position = grenade.bind().get_position();
+        let query = Query::Point {
+          position: Vec3::new(position.x, position.y, position.z),
+        };
+        let handle = grenade_batch.push_filtered(query, grenade_filter);
+        (*shape, handle)
+      })
+      .collect();
+
+    let results = sdf_controller.bind_mut().compute_queries(&queries);
+
+    let (collision, hit_point) = match (&previous_player, &current_player) {
+      (Some((sweep, previous_results)), _) => sweep.collision(previous_results),
+      (None, Some(sweep)) => sweep.collision(&results),
+      (None, None) => (Vector4::new(0.0, 0.0, 0.0, 1.0), None),
+    };
+
+    // the surface is looked up where the earliest point hit it
+    let surface = match hit_point {
+      Some(hit) => sdf_controller.bind().material_at(hit, player_filter),
+      None => Material::default(),
+    };
+
+    player
+      .signals()
+      .update_pos()
+      .emit(dt as f32, collision, surface.friction, surface.bounciness);
+
+    let grenade_events: Vec<(ShapeHandle, Vector4)> = if self.latency_tolerant_grenades {
+      match &previous_results {
+        Some(previous_results) => previous_queries
+          .grenades
+          .iter()
+          .map(|(shape, handle)| (*shape, previous_results.get(*handle).unwrap()))
+          .collect(),
+        None => Vec::new(),
       }
-    }
+    } else {
+      grenade_queries
+        .iter()
+        .map(|(shape, handle)| (*shape, results.get(*handle).unwrap()))
+        .collect()
+    };
 
-    player.signals().update_pos().emit(dt as f32, collision);
-
-    if self.grenades.len() > 0 {
-      let grenade_colliders = self.get_grenade_colliders();
-      let collision_events = self
-        .sdf_controller()
-        .bind_mut()
-        .compute_collision(grenade_colliders);
-
-      for i in 0..self.grenades.len() {
-        if collision_events[i].w < 0.0 {
-          self.grenades[i]
-            .1
-            .signals()
-            .collision()
-            .emit(collision_events[i]);
-        }
+    for (shape, event) in grenade_events {
+      if event.w >= 0.0 {
+        continue;
+      }
+      // grenades removed since the query was submitted are skipped
+      if let Some((_, grenade)) = self.grenades.iter().find(|(id, _)| *id == shape) {
+        let surface = sdf_controller
+          .bind()
+          .material_at(grenade.bind().get_position(), grenade_filter);
+        grenade
+          .signals()
+          .collision()
+          .emit(event, surface.friction, surface.bounciness);
       }
     }
 
+    sdf_controller.bind_mut().submit_queries(&async_queries);
+    self.pending_queries = PendingQueries {
+      player: next_player,
+      grenades: if self.latency_tolerant_grenades {
+        grenade_queries
+      } else {
+        Vec::new()
+      },
+    };
+
     let mut transform = sdf_controller.get_transform();
     transform.origin = player.get_transform().origin;
     sdf_controller.set_transform(transform);
-    for i in 0..self.grenades.len() {
-      if i >= self.grenades.len() {
-        break;
-      }
-
-      let (i, grenade) = self.grenades[i].clone();
+    for (shape, grenade) in self.grenades.clone() {
       if grenade.bind().exploded {
         player
           .signals()
           .explosion()
           .emit(grenade.bind().get_position());
-        self.on_remove_grenade(i as i32);
+        self.remove_grenade(shape);
       } else {
         let position: Vector3 = grenade.bind().get_position();
-        sdf_controller.bind_mut().update_shape(
-          i.clone(),
-          Vector4::new(
-            position.x,
-            position.y,
-            position.z,
-            sdf_controller::FLAG_NO_COLLISION,
-          ),
-          grenade::PROPERTIES,
-          grenade::COLOR,
-        );
+        let grenade_shape = self.grenade_shape(position);
+        // a grenade at rest leaves the scene's revision, and the uploads
+        // keyed on it, alone
+        if let Err(e) = sdf_controller.bind_mut().set_shape(shape, &grenade_shape) {
+          godot_error!("Failed to move grenade: {}", e);
+        }
       }
     }
   }
@@ -128,56 +290,55 @@ impl INode3D for GameController {
 impl GameController {
   fn on_spawn_grenade(&mut self, position: Vector3, direction: Vector3) {
     let mut sdf_controller = self.sdf_controller();
-    match sdf_controller.bind_mut().new_shape(
-      Vector4::new(position.x, position.y, position.z, 1.0),
-      grenade::PROPERTIES,
-      grenade::COLOR,
-    ) {
-      Ok(address) => {
+    let shape = self.grenade_shape(position);
+    match sdf_controller.bind_mut().insert_dynamic_shape(&shape) {
+      Ok(shape) => {
         let mut grenade = self.grenade_scene.instantiate_as::<Grenade>();
 
         grenade
           .bind_mut()
-          .initialize(position, direction * GRENADE_SPEED, address as i32);
+          .initialize(position, direction * GRENADE_SPEED);
 
         self.base_mut().add_child(&grenade);
 
-        self.grenades.push((address, grenade));
+        self.grenades.push((shape, grenade));
       }
-      Err(e) => panic!("{}", e),
+      Err(e) => godot_error!("Failed to spawn grenade: {}", e),
     };
   }
 
-  fn on_remove_grenade(&mut self, grenade_id: i32) {
-    let mut sdf_controller = self.sdf_controller();
-
-    let mut remove_id = 0;
-    for i in 0..self.grenades.len() {
-      let (id, mut grenade) = self.grenades[i].clone();
-
-      if id.clone() == grenade_id as usize {
-        sdf_controller.bind_mut().remove_shape(id.clone());
-        remove_id = i;
-        grenade.bind_mut().destroy();
-      }
+  /// Starts the new level with the player at its spawn. The grenades' shapes
+  /// went with the old scene, so only their nodes are left to free.
+  fn on_map_loaded(&mut self, _path: GString, player_spawn: Vector3) {
+    for (_, mut grenade) in self.grenades.drain(..) {
+      grenade.bind_mut().destroy();
     }
+    self.pending_queries = PendingQueries::default();
+    self.player().bind_mut().respawn(player_spawn);
+  }
 
-    self.grenades.remove(remove_id);
+  fn on_remove_grenade(&mut self, shape: Gd<shape_handle::ShapeHandle>) {
+    self.remove_grenade(shape.bind().handle());
   }
 
-  fn get_grenade_colliders(&mut self) -> PackedVector4Array {
-    let mut colliders = PackedVector4Array::new();
-    self.grenades.iter().for_each(|(_, grenade)| {
-      let position = grenade.bind().get_position();
-      colliders.push(Vector4::new(
-        position.x,
-        position.y,
-        position.z,
-        sdf_controller::FLAG_NO_COLLISION,
-      ));
-    });
+  fn remove_grenade(&mut self, shape: ShapeHandle) {
+    let Some(index) = self.grenades.iter().position(|(id, _)| *id == shape) else {
+      return;
+    };
+
+    let (_, mut grenade) = self.grenades.remove(index);
+    if let Err(e) = self.sdf_controller().bind_mut().remove_shape(shape) {
+      godot_error!("Failed to remove grenade: {}", e);
+    }
+    grenade.bind_mut().destroy();
+  }
 
-    return colliders;
+  fn grenade_shape(&self, position: Vector3) -> SdfShape {
+    let shape = grenade::shape(position);
+    if self.carve_grenades {
+      return shape.with_operation(Operation::Subtract, None);
+    }
+    return shape;
   }
 
   fn player(&mut self) -> Gd<Player> {
@@ -191,10 +352,32 @@ impl GameController {
   }
 }
 
+/// The earliest hit among the player's swept points, along with the index of
+/// the point that hit.
+fn player_collision(
+  results: &QueryResults<Vector4>,
+  handles: &[QueryHandle],
+) -> (Vector4, Option<usize>) {
+  let mut lowest_dist = 1.0;
+  let mut collision = Vector4::new(0.0, 0.0, 0.0, 1.0);
+  let mut hit_point = None;
+  for (index, handle) in handles.iter().enumerate() {
+    let Some(event) = results.get(*handle) else {
+      continue;
+    };
+    if event.length() != 0.0 && event.w < lowest_dist {
+      lowest_dist = collision.w;
+      collision = event;
+      hit_point = Some(index);
+    }
+  }
+  return (collision, hit_point);
+}
+
 #[godot_api]
 impl GameController {
   #[signal]
   pub fn spawn_grenade(position: Vector3, direction: Vector3);
   #[signal]
-  pub fn remove_grenade(grenade_id: i32);
+  pub fn remove_grenade(shape: Gd<shape_handle::ShapeHandle>);
 }
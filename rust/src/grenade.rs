@@ -1,18 +1,16 @@
 use godot::prelude::*;
-
-pub const PROPERTIES: Vector4 = Vector4 {
-  x: 0.01,
-  y: 0.0,
-  z: 0.0,
-  w: 1.0,
-};
-
-pub const COLOR: Vector4 = Vector4 {
-  x: 1.0,
-  y: 0.0,
-  z: 1.0,
-  w: 0.0,
-};
+use sdf_core::{SdfShape, ShapeFlags, ShapeKind, Vec3};
+
+/// The shape a grenade at `position` is drawn as, it never collides with the
+/// queries that move it.
+pub fn shape(position: Vector3) -> SdfShape {
+  return SdfShape::new(
+    ShapeKind::Sphere { radius: 0.01 },
+    Vec3::new(position.x, position.y, position.z),
+  )
+  .with_color(Vec3::new(1.0, 0.0, 1.0))
+  .with_flags(ShapeFlags::RENDER);
+}
 
 #[derive(GodotClass)]
 #[class(base = Node3D)]
@@ -21,7 +19,6 @@ pub struct Grenade {
   base: Base<Node3D>,
 
   velocity: Vector3,
-  pub grenade_id: i32,
   pub exploded: bool,
   destroyed: bool,
 }
@@ -32,7 +29,6 @@ impl INode3D for Grenade {
     return Self {
       base,
       velocity: Vector3::ZERO,
-      grenade_id: 0,
       exploded: false,
       destroyed: false,
     };
@@ -59,10 +55,9 @@ impl INode3D for Grenade {
 }
 
 impl Grenade {
-  pub fn initialize(&mut self, position: Vector3, velocity: Vector3, grenade_id: i32) {
+  pub fn initialize(&mut self, position: Vector3, velocity: Vector3) {
     self.add_position(position);
     self.velocity = velocity;
-    self.grenade_id = grenade_id;
   }
 
   pub fn add_position(&mut self, offset: Vector3) {
@@ -75,8 +70,23 @@ impl Grenade {
     return self.base().get_transform().origin;
   }
 
-  fn on_collision(&mut self, _collision: Vector4) {
-    self.exploded = true;
+  /// Explodes on surfaces that do not bounce, otherwise the velocity into the
+  /// surface is reflected and the velocity along it loses `friction` of
+  /// itself.
+  fn on_collision(&mut self, collision: Vector4, friction: f32, bounciness: f32) {
+    if bounciness <= 0.0 {
+      self.exploded = true;
+      return;
+    }
+
+    let normal = Vector3::new(collision.x, collision.y, collision.z).normalized();
+    let into = self.velocity.dot(normal);
+    if into < 0.0 {
+      let tangent = self.velocity - normal * into;
+      self.velocity = tangent * (1.0 - friction.clamp(0.0, 1.0)) - normal * into * bounciness;
+    }
+    // back out of the surface the grenade is inside of
+    self.add_position(normal * -collision.w);
   }
 
   pub fn destroy(&mut self) {
@@ -87,5 +97,5 @@ impl Grenade {
 #[godot_api]
 impl Grenade {
   #[signal]
-  pub fn collision(collision: Vector4);
+  pub fn collision(collision: Vector4, friction: f32, bounciness: f32);
 }
@@ -1,9 +1,13 @@
 use godot::prelude::*;
 
+mod collision_backend;
 mod game_controller;
 mod grenade;
+mod parity_check;
 mod player;
 mod sdf_controller;
+mod shape_dictionary;
+mod shape_handle;
 
 struct RustExtension;
 
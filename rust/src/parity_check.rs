@@ -0,0 +1,135 @@
+use godot::classes::{DirAccess, FileAccess, Os};
+use godot::prelude::*;
+use sdf_core::parity::{compare_events, sample_points, sample_sweeps, Tolerance};
+use sdf_core::scene::DEFAULT_MAX_SHAPES;
+use sdf_core::{map_file, Scene, Vec4};
+
+use crate::collision_backend::{
+  from_vector4, to_packed_array, to_vector4, CollisionBackend, CpuBackend, GpuBackend,
+};
+
+/// Pass after `--` on the godot command line to run the check and quit,
+/// e.g. `godot --path godot -- --check-parity`.
+pub const PARITY_ARG: &str = "--check-parity";
+
+const FIXTURE_DIR: &str = "res://fixtures";
+const DEFAULT_MAP_PATH: &str = "res://default_map.ron";
+
+const NUM_POINTS: usize = 4096;
+const NUM_SWEEPS: usize = 256;
+const POINTS_PER_SWEEP: usize = 19;
+const MAX_SPEED: f32 = 2.0;
+
+// sphere tracing can take a different number of steps on grazing rays
+const SHAPECAST_TOLERANCE: Tolerance = Tolerance {
+  distance: 1e-2,
+  normal_alignment: 0.95,
+};
+const MAX_SHAPECAST_MISMATCH_RATIO: f32 = 0.01;
+
+pub fn requested() -> bool {
+  return Os::singleton()
+    .get_cmdline_user_args()
+    .contains(&GString::from(PARITY_ARG));
+}
+
+/// Runs every fixture map through both backends and reports any results
+/// that disagree, returns whether the backends matched.
+pub fn run(blend_factor: f32) -> bool {
+  let Some(mut gpu) = GpuBackend::new(DEFAULT_MAX_SHAPES) else {
+    godot_error!("Parity check needs a local rendering device");
+    return false;
+  };
+  let mut cpu = CpuBackend::default();
+
+  let mut passed = true;
+  let fixtures = fixture_paths();
+  if fixtures.len() == 1 {
+    godot_error!("No fixture maps found in {}", FIXTURE_DIR);
+    passed = false;
+  }
+  for path in fixtures {
+    let path = path.as_str();
+    let mut scene = Scene::new(blend_factor);
+    let content = FileAccess::get_file_as_string(path).to_string();
+    if let Err(e) = map_file::load(&mut scene, path, &content) {
+      godot_error!("{}", e);
+      passed = false;
+      continue;
+    }
+    scene.update_caches();
+
+    let points = to_packed_array(&sample_points(&scene, NUM_POINTS, 1));
+    let expected = cpu.compute_collision(&scene, points.clone());
+    let actual = gpu.compute_collision(&scene, points);
+    let mismatches = compare_events(
+      &to_events(expected),
+      &to_events(actual),
+      Tolerance::default(),
+    );
+    if !mismatches.is_empty() {
+      godot_error!(
+        "{}: {} of {} collision queries differ, first {:?}",
+        path,
+        mismatches.len(),
+        NUM_POINTS,
+        mismatches[0]
+      );
+      passed = false;
+    }
+
+    let mut num_casts = 0;
+    let mut num_mismatches = 0;
+    for sweep in sample_sweeps(&scene, NUM_SWEEPS, POINTS_PER_SWEEP, MAX_SPEED, 2) {
+      let points = to_packed_array(&sweep.points);
+      let velocity = to_vector4(sweep.velocity);
+      let expected = cpu.compute_shapecast(&scene, points.clone(), velocity);
+      let actual = gpu.compute_shapecast(&scene, points, velocity);
+
+      num_casts += sweep.points.len();
+      num_mismatches += compare_events(
+        &to_events(expected),
+        &to_events(actual),
+        SHAPECAST_TOLERANCE,
+      )
+      .len();
+    }
+    if num_mismatches as f32 > num_casts as f32 * MAX_SHAPECAST_MISMATCH_RATIO {
+      godot_error!(
+        "{}: {} of {} shapecast queries differ",
+        path,
+        num_mismatches,
+        num_casts
+      );
+      passed = false;
+    }
+
+    godot_print!(
+      "{}: checked {} points and {} sweeps",
+      path,
+      NUM_POINTS,
+      num_casts
+    );
+  }
+
+  return passed;
+}
+
+/// The default map and every map in the fixtures directory, so a new fixture
+/// is checked as soon as it is added.
+fn fixture_paths() -> Vec<String> {
+  let mut fixtures: Vec<String> = DirAccess::get_files_at(FIXTURE_DIR)
+    .as_slice()
+    .iter()
+    .map(|name| name.to_string())
+    .filter(|name| map_file::is_map_path(name))
+    .map(|name| format!("{}/{}", FIXTURE_DIR, name))
+    .collect();
+  fixtures.sort();
+  fixtures.insert(0, DEFAULT_MAP_PATH.to_string());
+  return fixtures;
+}
+
+fn to_events(events: Vec<Vector4>) -> Vec<Vec4> {
+  return events.into_iter().map(from_vector4).collect();
+}
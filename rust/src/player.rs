@@ -50,6 +50,11 @@ pub struct Player {
   mouse_captured: bool,
   look_rotation: Vector2,
   grounded: bool,
+
+  /// Resolve the player's shapecast asynchronously, a frame late, instead of
+  /// waiting on the GPU.
+  #[export]
+  pub latency_tolerant_shapecast: bool,
 }
 
 #[godot_api]
@@ -61,6 +66,7 @@ impl INode3D for Player {
       mouse_captured: false,
       look_rotation: Vector2::new(0.0, 0.0),
       grounded: false,
+      latency_tolerant_shapecast: false,
     };
   }
 
@@ -187,24 +193,29 @@ const Y_AXIS: Vector3 = Vector3 {
 };
 
 impl Player {
-  pub fn on_update_pos(&mut self, dt: f32, shapecast: Vector4) {
+  /// `friction` and `bounciness` are the hit surface's material, a
+  /// frictionless surface keeps all of the horizontal momentum.
+  pub fn on_update_pos(&mut self, dt: f32, shapecast: Vector4, friction: f32, bounciness: f32) {
     if shapecast.w < 1.0 {
       let normal = Vector3::new(shapecast.x, shapecast.y, shapecast.z).normalized();
 
       let free_velocity = self.velocity * shapecast.w * 0.9;
       let remaining_velocity = self.velocity - free_velocity;
       let slide_velocity = project_on_plane(remaining_velocity, normal);
+      let bounce_velocity = normal * -remaining_velocity.dot(normal).min(0.0) * bounciness;
 
-      self.velocity = free_velocity + slide_velocity;
+      self.velocity = free_velocity + slide_velocity + bounce_velocity;
 
       let vertical = self.velocity.dot(Y_AXIS) * Y_AXIS;
       let horizontal = self.velocity - vertical;
 
-      if self.velocity.length() < FAST_THRESHOLD {
-        self.velocity = horizontal * MOMENTUM + vertical;
+      let momentum = if self.velocity.length() < FAST_THRESHOLD {
+        MOMENTUM
       } else {
-        self.velocity = horizontal * FAST_MOMENTUM + vertical;
-      }
+        FAST_MOMENTUM
+      };
+      let momentum = 1.0 - (1.0 - momentum) * friction.clamp(0.0, 1.0);
+      self.velocity = horizontal * momentum + vertical;
 
       if normal.dot(Y_AXIS) > 0.5 {
         self.grounded = true;
@@ -227,6 +238,13 @@ impl Player {
     }
   }
 
+  /// Moves the player to `position` at rest, e.g. at the start of a level.
+  pub fn respawn(&mut self, position: Vector3) {
+    self.base_mut().set_position(position);
+    self.velocity = Vector3::ZERO;
+    self.grounded = false;
+  }
+
   pub fn get_position(&self) -> Vector3 {
     return self.base().get_transform().origin;
   }
@@ -289,7 +307,7 @@ impl Player {
 #[godot_api]
 impl Player {
   #[signal]
-  pub fn update_pos(dt: f32, shapecast: Vector4);
+  pub fn update_pos(dt: f32, shapecast: Vector4, friction: f32, bounciness: f32);
   #[signal]
   pub fn explosion(position: Vector3);
 }
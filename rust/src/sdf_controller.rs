@@ -1,30 +1,62 @@
-use godot::classes::file_access::ModeFlags;
 // use crate::game_controller::GameController;
-use godot::classes::rendering_device::UniformType;
+use godot::classes::file_access::ModeFlags;
+use godot::classes::image::Format;
 use godot::classes::{
-  FileAccess, IMeshInstance3D, Input, MeshInstance3D, RdShaderFile, RdUniform, RenderingDevice,
-  RenderingServer, ShaderMaterial,
+  DirAccess, FileAccess, IMeshInstance3D, Image, ImageTexture, Input, MeshInstance3D,
+  ShaderMaterial,
 };
-use godot::global::Key;
+use godot::global::{Error, Key};
 use godot::prelude::*;
-
-const COLLISION_SHADER_PATH: &str = "res://collision.glsl";
-const SHAPECAST_SHADER_PATH: &str = "res://shapecast.glsl";
-
-const MAX_SHAPES: usize = 100;
+use sdf_core::map_file::{self, AppliedMap, Entity, MapFile};
+use sdf_core::material::MAX_MATERIALS;
+use sdf_core::query::{QueryBatch, QueryFilter, QueryResults};
+use sdf_core::scene::DEFAULT_MAX_SHAPES;
+use sdf_core::{Material, Scene, SdfShape, ShapeFlags, ShapeHandle, Vec3, Vec4};
+
+use crate::collision_backend::{
+  create_backend, from_quaternion, from_vector4, to_packed_array, to_vector4, CollisionBackend,
+  CollisionBackendKind, CpuBackend,
+};
+use crate::parity_check;
+use crate::shape_dictionary::{shape_from_dictionary, shape_to_dictionary};
+use crate::shape_handle;
 
 const BLEND_FACTOR: &str = "BLEND_FACTOR";
 const BACKGROUND: &str = "BACKGROUND_COLOR";
-const POSITIONS: &str = "POSITIONS";
-const PROPERTIES: &str = "PROPERTIES";
-const COLORS: &str = "COLORS";
+const SHAPE_DATA: &str = "SHAPE_DATA";
+const SHAPE_COUNT: &str = "SHAPE_COUNT";
+const SHAPE_GRID: &str = "SHAPE_GRID";
+const GRID_ORIGIN: &str = "GRID_ORIGIN";
+const GRID_SIZE: &str = "GRID_SIZE";
+const SHAPE_INSTRUCTIONS: &str = "SHAPE_INSTRUCTIONS";
+const INSTRUCTION_COUNT: &str = "INSTRUCTION_COUNT";
+const MATERIAL_DATA: &str = "MATERIAL_DATA";
+const MATERIAL_COUNT: &str = "MATERIAL_COUNT";
+
+// rows of the shape data texture: positions, properties, colors, modifiers
+// and rotations
+const SHAPE_DATA_ROWS: i32 = 5;
+// widest texture godot guarantees, one column per shape
+const MAX_SHAPE_DATA_WIDTH: i32 = 16384;
+// `GRID_DATA_WIDTH` in the renderer, the grid wraps onto more rows past it
+const GRID_DATA_WIDTH: usize = 4096;
+// `INSTRUCTION_DATA_WIDTH` in the renderer
+const INSTRUCTION_DATA_WIDTH: usize = 4096;
+const DEFAULT_MAP_PATH: &str = "res://default_map.ron";
+// player-made maps, `res://` is read only in exported games
+const USER_MAP_DIR: &str = "user://maps";
+// where TAB saves the scene within `user_map_dir`
+const SAVED_MAP_NAME: &str = "saved_map.ron";
+// seconds between checks of the map's modification time in `watch_map` mode
+const MAP_WATCH_INTERVAL: f64 = 0.5;
+// rows of the material data texture, see `Material::pack`
+const MATERIAL_DATA_ROWS: i32 = 3;
 
 #[allow(unused)]
-pub const FLAG_COLLISION: f32 = 0.0;
-#[allow(unused)]
-pub const FLAG_NO_COLLISION: f32 = 1.0;
-#[allow(unused)]
-pub const FLAG_NO_RENDER: f32 = 2.0;
+pub use sdf_core::scene::{
+  BLEND_SCENE, FLAGS_SOLID, FLAG_CAST_SHADOW, FLAG_COLLIDE, FLAG_GRENADE_ONLY, FLAG_PLAYER_ONLY,
+  FLAG_RENDER, FLAG_TRIGGER, OP_INTERSECT, OP_SUBTRACT, OP_UNION,
+};
 
 #[derive(GodotClass)]
 #[class(base = MeshInstance3D)]
@@ -34,24 +66,56 @@ pub struct SdfController {
 
   #[export]
   blend_factor: f32,
-  rendering_device: Gd<RenderingDevice>,
+  #[export]
+  collision_backend: CollisionBackendKind,
+  backend: Box<dyn CollisionBackend>,
+  /// Most shapes the scene can hold, sizes the GPU buffers and the renderer's
+  /// shape texture. Only read when the node enters the tree.
+  #[export]
+  max_shapes: i32,
+  /// Map loaded when the node becomes ready, then the level currently
+  /// loaded. `res://` and `user://` paths both work.
+  #[export(file = "*.ron,*.txt")]
+  map_path: GString,
+  /// Levels `next_level` and `previous_level` step through, followed by the
+  /// maps in `user_map_dir`.
+  #[export]
+  levels: PackedStringArray,
+  /// Directory of player-made maps, which TAB saves into. Empty to keep
+  /// player-made maps out of the level sequence.
+  #[export(dir)]
+  user_map_dir: GString,
+  /// Reload the current level with `reload_map` whenever its file changes on
+  /// disk, for editing maps while the game runs.
+  #[export]
+  watch_map: bool,
 
   background_color: ColorHsv,
-  positions: PackedVector4Array,
-  properties: PackedVector4Array,
-  colors: PackedVector4Array,
-
-  num_shapes: usize,
-  shapes_used: [bool; MAX_SHAPES],
+  scene: Scene,
+  shape_data: Option<Gd<ImageTexture>>,
+  shape_data_revision: Option<u64>,
+  shape_grid: Option<Gd<ImageTexture>>,
+  shape_grid_built_for: Option<(u64, f32)>,
+  shape_instructions: Option<Gd<ImageTexture>>,
+  shape_instructions_revision: Option<u64>,
+  material_data: Option<Gd<ImageTexture>>,
+  material_data_revision: Option<u64>,
+  /// Entities of the loaded map, written back by `save_map`.
+  entities: Vec<Entity>,
+  /// Shapes and groups of the loaded map, which `reload_map` replaces.
+  applied_map: AppliedMap,
+  /// Shapes the game spawned rather than the level, which `save_map` leaves
+  /// out.
+  dynamic_shapes: Vec<ShapeHandle>,
+  map_modified_time: u64,
+  map_watch_timer: f64,
+  /// Keys down last frame, so shortcuts fire once per press.
+  held_keys: Vec<Key>,
 }
 
 #[godot_api]
 impl IMeshInstance3D for SdfController {
   fn init(base: Base<MeshInstance3D>) -> Self {
-    let rendering_device = RenderingServer::singleton()
-      .create_local_rendering_device()
-      .unwrap();
-
     return Self {
       base,
       background_color: ColorHsv {
@@ -61,23 +125,84 @@ impl IMeshInstance3D for SdfController {
         a: 1.0,
       },
       blend_factor: 1.0,
-      positions: PackedArray::from([Vector4::ZERO; MAX_SHAPES]),
-      properties: PackedArray::from([Vector4::ZERO; MAX_SHAPES]),
-      colors: PackedArray::from([Vector4::ZERO; MAX_SHAPES]),
-      num_shapes: 0,
-      shapes_used: [false; MAX_SHAPES],
-      rendering_device,
+      collision_backend: CollisionBackendKind::Auto,
+      backend: Box::new(CpuBackend::default()),
+      max_shapes: DEFAULT_MAX_SHAPES as i32,
+      map_path: DEFAULT_MAP_PATH.into(),
+      levels: PackedStringArray::from(&[GString::from(DEFAULT_MAP_PATH)]),
+      user_map_dir: USER_MAP_DIR.into(),
+      watch_map: false,
+      scene: Scene::default(),
+      shape_data: None,
+      shape_instructions: None,
+      shape_instructions_revision: None,
+      shape_data_revision: None,
+      shape_grid: None,
+      shape_grid_built_for: None,
+      material_data: None,
+      material_data_revision: None,
+      entities: Vec::new(),
+      applied_map: AppliedMap::default(),
+      dynamic_shapes: Vec::new(),
+      map_modified_time: 0,
+      map_watch_timer: 0.0,
+      held_keys: Vec::new(),
     };
   }
 
   fn ready(&mut self) {
-    self.load_map();
+    if parity_check::requested() {
+      let exit_code = if parity_check::run(self.blend_factor) {
+        0
+      } else {
+        1
+      };
+      self
+        .base()
+        .get_tree()
+        .unwrap()
+        .quit_ex()
+        .exit_code(exit_code)
+        .done();
+      return;
+    }
+
+    let path = self.map_path.clone();
+    self.load_map(path);
+  }
+
+  fn enter_tree(&mut self) {
+    if !(1..=MAX_SHAPE_DATA_WIDTH).contains(&self.max_shapes) {
+      godot_warn!(
+        "max_shapes must be between 1 and {}, got {}",
+        MAX_SHAPE_DATA_WIDTH,
+        self.max_shapes
+      );
+      self.max_shapes = self.max_shapes.clamp(1, MAX_SHAPE_DATA_WIDTH);
+    }
+
+    let max_shapes = self.max_shapes as usize;
+    if self.scene.max_shapes() != max_shapes {
+      self.scene = Scene::with_max_shapes(self.blend_factor, max_shapes);
+      self.shape_data = None;
+      self.shape_data_revision = None;
+      self.shape_instructions_revision = None;
+      self.material_data_revision = None;
+    }
+    self.backend = create_backend(self.collision_backend, max_shapes);
+  }
+
+  fn exit_tree(&mut self) {
+    // drops the GPU backend so its pipelines and buffers are freed now
+    self.backend = Box::new(CpuBackend::default());
   }
 
   fn physics_process(&mut self, dt: f64) {
     if self.blend_factor < 0.0 {
       self.blend_factor = 0.0;
     }
+    self.scene.blend_factor = self.blend_factor;
+    self.scene.update_caches();
 
     let mut material = self
       .base_mut()
@@ -94,380 +219,644 @@ impl IMeshInstance3D for SdfController {
 
     material.set_shader_parameter(BLEND_FACTOR, &self.blend_factor.to_variant());
     material.set_shader_parameter(BACKGROUND, &self.background_color.to_rgb().to_variant());
-    material.set_shader_parameter(POSITIONS, &self.positions.to_variant());
-    material.set_shader_parameter(PROPERTIES, &self.properties.to_variant());
-    material.set_shader_parameter(COLORS, &self.colors.to_variant());
-
-    if Input::singleton().is_key_pressed(Key::TAB) {
-      self.print_map();
+    material.set_shader_parameter(SHAPE_DATA, &self.shape_data().to_variant());
+    material.set_shader_parameter(SHAPE_COUNT, &(self.scene.num_shapes() as i32).to_variant());
+    material.set_shader_parameter(SHAPE_GRID, &self.shape_grid().to_variant());
+    let [origin, size] = self
+      .scene
+      .grid()
+      .map_or([Vec4::ZERO; 2], |grid| grid.header());
+    material.set_shader_parameter(GRID_ORIGIN, &to_vector4(origin).to_variant());
+    material.set_shader_parameter(GRID_SIZE, &to_vector4(size).to_variant());
+    material.set_shader_parameter(SHAPE_INSTRUCTIONS, &self.shape_instructions().to_variant());
+    let num_instructions = self
+      .scene
+      .instructions()
+      .map_or(0, |instructions| instructions.len());
+    material.set_shader_parameter(INSTRUCTION_COUNT, &(num_instructions as i32).to_variant());
+    material.set_shader_parameter(MATERIAL_DATA, &self.material_data().to_variant());
+    let num_materials = self.scene.materials().len();
+    material.set_shader_parameter(MATERIAL_COUNT, &(num_materials as i32).to_variant());
+
+    if self.key_just_pressed(Key::TAB) {
+      let path = self.saved_map_path();
+      if self.save_map(path.clone()) {
+        godot_print!("Saved map to {}", path);
+      }
     }
-    if Input::singleton().is_key_pressed(Key::Q) {
-      self.load_map();
+    if self.key_just_pressed(Key::Q) {
+      self.reload_map();
+    }
+    if self.key_just_pressed(Key::BRACKETRIGHT) {
+      self.next_level();
+    }
+    if self.key_just_pressed(Key::BRACKETLEFT) {
+      self.previous_level();
+    }
+
+    if self.watch_map {
+      self.map_watch_timer += dt;
+      if self.map_watch_timer >= MAP_WATCH_INTERVAL {
+        self.map_watch_timer = 0.0;
+        if FileAccess::get_modified_time(&self.map_path) != self.map_modified_time {
+          godot_print!("Reloading {}", self.map_path);
+          self.reload_map();
+        }
+      }
     }
   }
 }
 
+#[godot_api]
 impl SdfController {
-  fn load_map(&mut self) {
-    self.positions = PackedArray::from([Vector4::ZERO; MAX_SHAPES]);
-    self.properties = PackedArray::from([Vector4::ZERO; MAX_SHAPES]);
-    self.colors = PackedArray::from([Vector4::ZERO; MAX_SHAPES]);
-    self.num_shapes = 0;
-    self.shapes_used = [false; MAX_SHAPES];
-
-    let file = FileAccess::open("res://default_map.txt", ModeFlags::READ).unwrap();
-    let content = file.get_as_text();
-    let content = content.split("\n");
-
-    for i in 0..content.len() {
-      let line = content[i].clone();
-      let properties = line.split("\t");
-      if properties.len() == 4 {
-        let mut split_properties = Vec::new();
-
-        for j in 0..properties.len() {
-          split_properties.push(properties[j].split(" "));
-        }
+  #[constant]
+  const SHAPE_FLAG_COLLIDE: i64 = ShapeFlags::COLLIDE.bits() as i64;
+  #[constant]
+  const SHAPE_FLAG_RENDER: i64 = ShapeFlags::RENDER.bits() as i64;
+  #[constant]
+  const SHAPE_FLAG_CAST_SHADOW: i64 = ShapeFlags::CAST_SHADOW.bits() as i64;
+  #[constant]
+  const SHAPE_FLAG_TRIGGER: i64 = ShapeFlags::TRIGGER.bits() as i64;
+  #[constant]
+  const SHAPE_FLAG_PLAYER_ONLY: i64 = ShapeFlags::PLAYER_ONLY.bits() as i64;
+  #[constant]
+  const SHAPE_FLAG_GRENADE_ONLY: i64 = ShapeFlags::GRENADE_ONLY.bits() as i64;
+
+  /// Also emitted for every shape a map load or reload adds.
+  #[signal]
+  pub fn shape_added(handle: Gd<shape_handle::ShapeHandle>);
+  /// Also emitted for every shape a map load or reload removes.
+  #[signal]
+  pub fn shape_removed(handle: Gd<shape_handle::ShapeHandle>);
+  /// Emitted after a map replaced the scene, following `shape_removed` and
+  /// `shape_added` for its shapes, with where the map places the player, the
+  /// origin for maps without a spawn.
+  #[signal]
+  pub fn map_loaded(path: GString, player_spawn: Vector3);
+
+  /// Adds the shape described by the dictionary, see `shape_from_dictionary`
+  /// for its fields. Null when the dictionary is invalid or the scene is
+  /// full.
+  #[func]
+  fn new_shape(&mut self, shape: Dictionary) -> Option<Gd<shape_handle::ShapeHandle>> {
+    let handle = shape_from_dictionary(&shape).and_then(|shape| self.insert_shape(&shape));
+    match handle {
+      Ok(handle) => return Some(shape_handle::ShapeHandle::from_handle(handle)),
+      Err(e) => {
+        godot_error!("Failed to add shape: {}", e);
+        return None;
+      }
+    }
+  }
 
-        let shape_key = split_properties[0][0].clone();
-        let position = split_properties[1].clone();
-        let properties = split_properties[2].clone();
-        let color = split_properties[3].clone();
+  /// Replaces every property of the shape, false when the handle is stale or
+  /// the dictionary invalid.
+  #[func]
+  fn update_shape(&mut self, handle: Gd<shape_handle::ShapeHandle>, shape: Dictionary) -> bool {
+    let handle = handle.bind().handle();
+    let result = shape_from_dictionary(&shape).and_then(|shape| self.set_shape(handle, &shape));
+    if let Err(e) = result {
+      godot_error!("Failed to update shape: {}", e);
+      return false;
+    }
+    return true;
+  }
 
-        let mut shape = 0.0;
-        if shape_key == GString::from("sphere") {
-          shape = 1.0;
-        } else if shape_key == GString::from("cube") {
-          shape = 2.0;
-        }
+  /// The shape's dictionary, empty when the handle is stale.
+  #[func]
+  fn get_shape(&self, handle: Gd<shape_handle::ShapeHandle>) -> Dictionary {
+    return self
+      .scene
+      .shape(handle.bind().handle())
+      .map_or_else(Dictionary::new, |shape| shape_to_dictionary(&shape));
+  }
 
-        let _ = self.new_shape(
-          Vector4::new(
-            position[1].to_string().parse::<f32>().unwrap(),
-            position[2].to_string().parse::<f32>().unwrap(),
-            position[3].to_string().parse::<f32>().unwrap(),
-            0.0,
-          ),
-          Vector4::new(
-            properties[1].to_string().parse::<f32>().unwrap(),
-            properties[2].to_string().parse::<f32>().unwrap(),
-            properties[3].to_string().parse::<f32>().unwrap(),
-            shape,
-          ),
-          Vector4::new(
-            color[1].to_string().parse::<f32>().unwrap(),
-            color[2].to_string().parse::<f32>().unwrap(),
-            color[3].to_string().parse::<f32>().unwrap(),
-            0.0,
-          ),
-        );
-      }
+  #[func(rename = remove_shape)]
+  fn remove_shape_from_script(&mut self, handle: Gd<shape_handle::ShapeHandle>) -> bool {
+    if let Err(e) = self.remove_shape(handle.bind().handle()) {
+      godot_error!("Failed to remove shape: {}", e);
+      return false;
     }
+    return true;
   }
 
-  fn print_map(&self) {
-    godot_print!("current map file");
-    for i in 0..MAX_SHAPES {
-      let position = self.positions[i];
-      let properties = self.properties[i];
-      let color = self.colors[i];
+  /// One `(normal.xyz, distance)` per point, to the shapes every query sees.
+  #[func]
+  pub fn compute_collision(&mut self, points: PackedVector4Array) -> Array<Vector4> {
+    self.scene.update_caches();
+    let events = self.backend.compute_collision(&self.scene, points);
+    return Array::from(events.as_slice());
+  }
 
-      if properties.w == 0.0 || position.w >= 1.0 {
-        continue;
+  /// One `(normal.xyz, fraction)` per point swept along `velocity`.
+  #[func]
+  pub fn compute_shapecast(
+    &mut self,
+    points: PackedVector4Array,
+    velocity: Vector4,
+  ) -> Array<Vector4> {
+    self.scene.update_caches();
+    let hits = self
+      .backend
+      .compute_shapecast(&self.scene, points, velocity);
+    return Array::from(hits.as_slice());
+  }
+
+  /// Writes the level, including shapes that neither collide nor render but
+  /// not the game's dynamic shapes like grenades, with the loaded map's
+  /// entities to `path` in the format `load_map` reads. False when the file
+  /// could not be written.
+  #[func]
+  pub fn save_map(&self, path: GString) -> bool {
+    let content = map_file::save(&self.scene, &self.entities, &self.dynamic_shapes);
+    let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+      godot_error!(
+        "Failed to save map to {}: {:?}",
+        path,
+        FileAccess::get_open_error()
+      );
+      return false;
+    };
+    if !file.store_string(&content) {
+      godot_error!("Failed to save map to {}", path);
+      return false;
+    }
+    return true;
+  }
+
+  /// Replaces the scene with the map at `path`, in either format, and makes
+  /// it the current level. A map that fails to read or does not fit leaves
+  /// the current level in place and returns false.
+  #[func]
+  pub fn load_map(&mut self, path: GString) -> bool {
+    let modified_time = FileAccess::get_modified_time(&path);
+    let Some(map) = read_map(&path) else {
+      return false;
+    };
+
+    let removed = self.scene.handles().to_vec();
+    match map.replace(&mut self.scene) {
+      Ok(applied) => self.applied_map = applied,
+      Err(e) => {
+        godot_error!("Failed to load map: {}", e.in_file(&path.to_string()));
+        return false;
       }
+    }
+    self.dynamic_shapes.clear();
+    self.entities = map.entities;
+    self.map_path = path.clone();
+    self.map_modified_time = modified_time;
 
-      let mut output = "".to_string();
+    let added = self.applied_map.shapes.clone();
+    self.emit_shape_changes(&removed, &added);
 
-      if properties.w == 1.0 {
-        output = format!("{}sphere\t", output);
-      } else if properties.w == 2.0 {
-        output = format!("{}cube\t", output);
+    let spawn = self.player_spawn().unwrap_or(Vector3::ZERO);
+    self.signals().map_loaded().emit(&path, spawn);
+    return true;
+  }
+
+  /// Updates the current level from its file in place. The handles of its
+  /// shapes and of every shape added since, like grenades, stay valid and the
+  /// player stays where they are.
+  #[func]
+  pub fn reload_map(&mut self) -> bool {
+    let path = self.map_path.clone();
+    // a broken file is reported once rather than on every check
+    self.map_modified_time = FileAccess::get_modified_time(&path);
+    let Some(map) = read_map(&path) else {
+      return false;
+    };
+
+    let previous = match map.reload(&mut self.scene, &self.applied_map) {
+      Ok(applied) => std::mem::replace(&mut self.applied_map, applied),
+      Err(e) => {
+        godot_error!("Failed to reload map: {}", e.in_file(&path.to_string()));
+        return false;
       }
+    };
+    self.entities = map.entities;
+
+    // shapes updated in place keep their handles, so are neither removed nor
+    // added
+    let current = &self.applied_map.shapes;
+    let removed: Vec<ShapeHandle> = previous
+      .shapes
+      .iter()
+      .filter(|handle| !current.contains(handle))
+      .copied()
+      .collect();
+    let added: Vec<ShapeHandle> = current
+      .iter()
+      .filter(|handle| !previous.shapes.contains(handle))
+      .copied()
+      .collect();
+    self.emit_shape_changes(&removed, &added);
+    return true;
+  }
 
-      output = format!(
-        "{}position {} {} {}\t",
-        output, position.x, position.y, position.z
-      );
+  /// Loads the level after the current one, wrapping around to the first.
+  /// The first level when the current map is not in the sequence.
+  #[func]
+  pub fn next_level(&mut self) -> bool {
+    return self.step_level(1);
+  }
 
-      output = format!(
-        "{}scale {} {} {}\t",
-        output, properties.x, properties.y, properties.z
-      );
+  /// Loads the level before the current one, wrapping around to the last.
+  #[func]
+  pub fn previous_level(&mut self) -> bool {
+    return self.step_level(-1);
+  }
 
-      output = format!("{}color {} {} {}", output, color.x, color.y, color.z);
+  /// `levels` followed by the maps in `user_map_dir`, sorted by name.
+  #[func]
+  pub fn level_sequence(&self) -> PackedStringArray {
+    let mut sequence = self.levels.clone();
+    if self.user_map_dir.is_empty() || !DirAccess::dir_exists_absolute(&self.user_map_dir) {
+      return sequence;
+    }
 
-      godot_print!("{}", output);
+    let mut user_maps: Vec<String> = DirAccess::get_files_at(&self.user_map_dir)
+      .as_slice()
+      .iter()
+      .map(|name| name.to_string())
+      .filter(|name| map_file::is_map_path(name))
+      .collect();
+    user_maps.sort();
+    for name in user_maps {
+      sequence.push(&self.user_map_path(&name));
     }
+    return sequence;
   }
+}
 
-  pub fn compute_collision(&mut self, points: PackedVector4Array) -> Vec<Vector4> {
-    let shader_code = load::<RdShaderFile>(COLLISION_SHADER_PATH)
-      .get_spirv()
-      .unwrap();
-    let collision_shader = self.rendering_device.shader_create_from_spirv(&shader_code);
-
-    let point_bytes = points.to_byte_array();
-    let position_bytes = self.positions.to_byte_array();
-    let property_bytes = self.properties.to_byte_array();
-    let data_bytes = PackedArray::from([self.blend_factor]).to_byte_array();
-
-    let point_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(point_bytes.len() as u32)
-      .data(&point_bytes)
-      .done();
-
-    let position_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(position_bytes.len() as u32)
-      .data(&position_bytes)
-      .done();
-
-    let property_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(property_bytes.len() as u32)
-      .data(&property_bytes)
-      .done();
-
-    let data_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(data_bytes.len() as u32)
-      .data(&data_bytes)
-      .done();
-
-    let mut points_uniform = RdUniform::new_gd();
-    points_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    points_uniform.set_binding(0);
-    points_uniform.add_id(point_buffer);
-
-    let mut position_uniform = RdUniform::new_gd();
-    position_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    position_uniform.set_binding(1);
-    position_uniform.add_id(position_buffer);
-
-    let mut property_uniform = RdUniform::new_gd();
-    property_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    property_uniform.set_binding(2);
-    property_uniform.add_id(property_buffer);
-
-    let mut data_uniform = RdUniform::new_gd();
-    data_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    data_uniform.set_binding(3);
-    data_uniform.add_id(data_buffer);
-
-    let uniform_set = self.rendering_device.uniform_set_create(
-      &Array::from(&[
-        points_uniform,
-        position_uniform,
-        property_uniform,
-        data_uniform,
-      ]),
-      collision_shader,
-      0,
-    );
+impl SdfController {
+  fn step_level(&mut self, step: isize) -> bool {
+    let sequence = self.level_sequence();
+    let num_levels = sequence.len() as isize;
+    if num_levels == 0 {
+      godot_warn!("No levels to step through");
+      return false;
+    }
 
-    let pipeline = self
-      .rendering_device
-      .compute_pipeline_create(collision_shader);
-    let compute_list = self.rendering_device.compute_list_begin();
-    self
-      .rendering_device
-      .compute_list_bind_compute_pipeline(compute_list, pipeline);
-    self
-      .rendering_device
-      .compute_list_bind_uniform_set(compute_list, uniform_set, 0);
-    self
-      .rendering_device
-      .compute_list_dispatch(compute_list, points.len() as u32, 1, 1);
-    self.rendering_device.compute_list_end();
-
-    self.rendering_device.submit();
-    self.rendering_device.sync();
-
-    let output_bytes = self.rendering_device.buffer_get_data(point_buffer);
-    let output = output_bytes.to_float32_array();
-
-    self.rendering_device.free_rid(uniform_set);
-    self.rendering_device.free_rid(point_buffer);
-    self.rendering_device.free_rid(position_buffer);
-    self.rendering_device.free_rid(property_buffer);
-    self.rendering_device.free_rid(data_buffer);
-    self.rendering_device.free_rid(pipeline);
-    self.rendering_device.free_rid(collision_shader);
-
-    let mut events = Vec::new();
-    for i in 0..(output.len() / 4) {
-      events.push(Vector4::new(
-        output[i * 4 + 0],
-        output[i * 4 + 1],
-        output[i * 4 + 2],
-        output[i * 4 + 3],
-      ));
+    let index = match sequence
+      .as_slice()
+      .iter()
+      .position(|path| *path == self.map_path)
+    {
+      Some(index) => (index as isize + step).rem_euclid(num_levels),
+      None if step > 0 => 0,
+      None => num_levels - 1,
+    };
+    return self.load_map(sequence[index as usize].clone());
+  }
+
+  /// Where TAB saves, in `user_map_dir` so the map joins the level sequence.
+  fn saved_map_path(&self) -> GString {
+    if self.user_map_dir.is_empty() {
+      return format!("user://{}", SAVED_MAP_NAME).into();
+    }
+    let error = DirAccess::make_dir_recursive_absolute(&self.user_map_dir);
+    if error != Error::OK {
+      godot_error!("Failed to create {}: {:?}", self.user_map_dir, error);
     }
-    return events;
+    return self.user_map_path(SAVED_MAP_NAME);
   }
 
-  pub fn compute_shapecast(
-    &mut self,
-    points: PackedVector4Array,
-    velocity: Vector4,
-  ) -> Vec<Vector4> {
-    let shader_code = load::<RdShaderFile>(SHAPECAST_SHADER_PATH)
-      .get_spirv()
-      .unwrap();
-    let collision_shader = self.rendering_device.shader_create_from_spirv(&shader_code);
-
-    let mut points = points;
-    points.push(velocity);
-
-    let point_bytes = points.to_byte_array();
-    let position_bytes = self.positions.to_byte_array();
-    let property_bytes = self.properties.to_byte_array();
-    let data_bytes = PackedArray::from([self.blend_factor]).to_byte_array();
-
-    let point_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(point_bytes.len() as u32)
-      .data(&point_bytes)
-      .done();
-
-    let position_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(position_bytes.len() as u32)
-      .data(&position_bytes)
-      .done();
-
-    let property_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(property_bytes.len() as u32)
-      .data(&property_bytes)
-      .done();
-
-    let data_buffer = self
-      .rendering_device
-      .storage_buffer_create_ex(data_bytes.len() as u32)
-      .data(&data_bytes)
-      .done();
-
-    let mut points_uniform = RdUniform::new_gd();
-    points_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    points_uniform.set_binding(0);
-    points_uniform.add_id(point_buffer);
-
-    let mut position_uniform = RdUniform::new_gd();
-    position_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    position_uniform.set_binding(1);
-    position_uniform.add_id(position_buffer);
-
-    let mut property_uniform = RdUniform::new_gd();
-    property_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    property_uniform.set_binding(2);
-    property_uniform.add_id(property_buffer);
-
-    let mut data_uniform = RdUniform::new_gd();
-    data_uniform.set_uniform_type(UniformType::STORAGE_BUFFER);
-    data_uniform.set_binding(3);
-    data_uniform.add_id(data_buffer);
-
-    let uniform_set = self.rendering_device.uniform_set_create(
-      &Array::from(&[
-        points_uniform,
-        position_uniform,
-        property_uniform,
-        data_uniform,
-      ]),
-      collision_shader,
-      0,
-    );
+  fn user_map_path(&self, name: &str) -> GString {
+    return format!(
+      "{}/{}",
+      self.user_map_dir.to_string().trim_end_matches('/'),
+      name
+    )
+    .into();
+  }
 
-    let pipeline = self
-      .rendering_device
-      .compute_pipeline_create(collision_shader);
-    let compute_list = self.rendering_device.compute_list_begin();
-    self
-      .rendering_device
-      .compute_list_bind_compute_pipeline(compute_list, pipeline);
+  /// `shape_removed` for each of `removed` then `shape_added` for each of
+  /// `added`.
+  fn emit_shape_changes(&mut self, removed: &[ShapeHandle], added: &[ShapeHandle]) {
+    for handle in removed {
+      self
+        .signals()
+        .shape_removed()
+        .emit(&shape_handle::ShapeHandle::from_handle(*handle));
+    }
+    for handle in added {
+      self
+        .signals()
+        .shape_added()
+        .emit(&shape_handle::ShapeHandle::from_handle(*handle));
+    }
+  }
+
+  /// True only on the frame `key` goes down.
+  fn key_just_pressed(&mut self, key: Key) -> bool {
+    let pressed = Input::singleton().is_key_pressed(key);
+    let held = self.held_keys.contains(&key);
+    if pressed && !held {
+      self.held_keys.push(key);
+    } else if !pressed && held {
+      self.held_keys.retain(|held_key| *held_key != key);
+    }
+    return pressed && !held;
+  }
+
+  /// Where the loaded map places the player, if anywhere.
+  pub fn player_spawn(&self) -> Option<Vector3> {
+    return map_file::player_spawn(&self.entities)
+      .map(|spawn| Vector3::new(spawn.x, spawn.y, spawn.z));
+  }
+
+  /// Resolves every query in the batch with a single dispatch.
+  pub fn compute_queries(&mut self, batch: &QueryBatch) -> QueryResults<Vector4> {
+    self.scene.update_caches();
+    return QueryResults::new(self.backend.compute_queries(
+      &self.scene,
+      batch.queries(),
+      batch.filters(),
+    ));
+  }
+
+  /// Starts resolving the batch without blocking, the results are read with
+  /// `collect_queries` on the next physics frame.
+  pub fn submit_queries(&mut self, batch: &QueryBatch) {
+    self.scene.update_caches();
     self
-      .rendering_device
-      .compute_list_bind_uniform_set(compute_list, uniform_set, 0);
+      .backend
+      .submit_queries(&self.scene, batch.queries(), batch.filters());
+  }
+
+  /// Results of the batch passed to the last `submit_queries`, if any.
+  pub fn collect_queries(&mut self) -> Option<QueryResults<Vector4>> {
+    return self.backend.collect_queries().map(QueryResults::new);
+  }
+
+  /// Emits `shape_added` with the new shape's handle.
+  pub fn insert_shape(&mut self, shape: &SdfShape) -> Result<ShapeHandle, &'static str> {
+    let handle = self.scene.insert_shape(shape)?;
     self
-      .rendering_device
-      .compute_list_dispatch(compute_list, points.len() as u32 - 1, 1, 1);
-    self.rendering_device.compute_list_end();
-
-    self.rendering_device.submit();
-    self.rendering_device.sync();
-
-    let output_bytes = self.rendering_device.buffer_get_data(point_buffer);
-    let output = output_bytes.to_float32_array();
-
-    self.rendering_device.free_rid(uniform_set);
-    self.rendering_device.free_rid(point_buffer);
-    self.rendering_device.free_rid(position_buffer);
-    self.rendering_device.free_rid(property_buffer);
-    self.rendering_device.free_rid(data_buffer);
-    self.rendering_device.free_rid(pipeline);
-    self.rendering_device.free_rid(collision_shader);
-
-    let mut events = Vec::new();
-    for i in 0..(output.len() / 4 - 1) {
-      events.push(Vector4::new(
-        output[i * 4 + 0],
-        output[i * 4 + 1],
-        output[i * 4 + 2],
-        output[i * 4 + 3],
-      ));
-    }
-    return events;
+      .signals()
+      .shape_added()
+      .emit(&shape_handle::ShapeHandle::from_handle(handle));
+    return Ok(handle);
   }
 
-  pub fn new_shape(
+  /// `insert_shape` for shapes that belong to the running game rather than
+  /// the level, like grenades, which `save_map` leaves out.
+  pub fn insert_dynamic_shape(&mut self, shape: &SdfShape) -> Result<ShapeHandle, &'static str> {
+    let handle = self.insert_shape(shape)?;
+    self.dynamic_shapes.push(handle);
+    return Ok(handle);
+  }
+
+  /// Replaces every property of the shape.
+  pub fn set_shape(&mut self, handle: ShapeHandle, shape: &SdfShape) -> Result<(), &'static str> {
+    return self.scene.set_shape(handle, shape);
+  }
+
+  /// Packed form of `insert_shape`: `position.w` is the flag and
+  /// `properties.w` the shape type. Does not emit `shape_added`.
+  pub fn new_packed_shape(
+    &mut self,
+    position: Vector4,
+    rotation: Quaternion,
+    properties: Vector4,
+    color: Vector4,
+  ) -> Result<ShapeHandle, &'static str> {
+    return self.scene.new_shape(
+      from_vector4(position),
+      from_quaternion(rotation),
+      from_vector4(properties),
+      from_vector4(color),
+    );
+  }
+
+  /// Packed form of `set_shape` that keeps the shape's operation and
+  /// rounding.
+  pub fn update_packed_shape(
     &mut self,
+    handle: ShapeHandle,
     position: Vector4,
+    rotation: Quaternion,
     properties: Vector4,
     color: Vector4,
+  ) -> Result<(), &'static str> {
+    return self.scene.update_shape(
+      handle,
+      from_vector4(position),
+      from_quaternion(rotation),
+      from_vector4(properties),
+      from_vector4(color),
+    );
+  }
+
+  /// Combines the shape with the scene through `operation`, one of the `OP_*`
+  /// constants, smoothed over `blend_radius` or `blend_factor` for
+  /// `BLEND_SCENE`.
+  pub fn set_operation(
+    &mut self,
+    handle: ShapeHandle,
+    operation: f32,
+    blend_radius: f32,
+  ) -> Result<(), &'static str> {
+    return self.scene.set_operation(handle, operation, blend_radius);
+  }
+
+  /// Queries only see the shape when their mask shares one of `layers`.
+  pub fn set_collision_layers(
+    &mut self,
+    handle: ShapeHandle,
+    layers: u32,
+  ) -> Result<(), &'static str> {
+    return self.scene.set_collision_layers(handle, layers);
+  }
+
+  /// Replaces the material shapes refer to with `id`.
+  pub fn set_material(&mut self, id: u32, material: Material) -> Result<(), &'static str> {
+    return self.scene.materials_mut().set(id, material);
+  }
+
+  /// The material of the surface nearest to `point` among the shapes
+  /// `filter` accepts.
+  pub fn material_at(&self, point: Vector3, filter: QueryFilter) -> Material {
+    return self
+      .scene
+      .material_at(Vec3::new(point.x, point.y, point.z), filter);
+  }
+
+  /// Emits `shape_removed` with the removed shape's now stale handle.
+  pub fn remove_shape(&mut self, handle: ShapeHandle) -> Result<(), &'static str> {
+    self.scene.remove_shape(handle)?;
+    self.dynamic_shapes.retain(|dynamic| *dynamic != handle);
+    self
+      .signals()
+      .shape_removed()
+      .emit(&shape_handle::ShapeHandle::from_handle(handle));
+    return Ok(());
+  }
+
+  /// Adds a group to `parent`, or to the scene itself for `None`. The shapes
+  /// and groups in it are combined on their own before the group is combined
+  /// with its parent through `operation`, smoothed over `blend_radius`.
+  pub fn new_group(
+    &mut self,
+    parent: Option<usize>,
+    operation: f32,
+    blend_radius: f32,
   ) -> Result<usize, &'static str> {
-    if self.num_shapes == MAX_SHAPES {
-      return Err("Cannot allocate new shape, maximum amount of shapes allocated");
+    return self.scene.new_group(parent, operation, blend_radius);
+  }
+
+  pub fn set_group_operation(
+    &mut self,
+    group: usize,
+    operation: f32,
+    blend_radius: f32,
+  ) -> Result<(), &'static str> {
+    return self
+      .scene
+      .set_group_operation(group, operation, blend_radius);
+  }
+
+  /// Moves the shape into `group`, or back into the scene itself for `None`.
+  pub fn set_shape_group(
+    &mut self,
+    handle: ShapeHandle,
+    group: Option<usize>,
+  ) -> Result<(), &'static str> {
+    return self.scene.set_shape_group(handle, group);
+  }
+
+  pub fn remove_group(&mut self, group: usize) -> Result<(), &'static str> {
+    return self.scene.remove_group(group);
+  }
+
+  /// The shape table as a `max_shapes` wide float texture for the renderer,
+  /// rebuilt only when the scene changed.
+  fn shape_data(&mut self) -> Gd<ImageTexture> {
+    if let Some(shape_data) = &self.shape_data {
+      if self.shape_data_revision == Some(self.scene.revision()) {
+        return shape_data.clone();
+      }
     }
 
-    for i in 0..MAX_SHAPES {
-      if !self.shapes_used[i] {
-        self.positions[i] = position;
-        self.properties[i] = properties;
-        self.colors[i] = color;
-        self.shapes_used[i] = true;
-        self.num_shapes += 1;
+    let width = self.scene.max_shapes();
+    let mut data = PackedVector4Array::new();
+    for row in [
+      self.scene.positions(),
+      self.scene.properties(),
+      self.scene.colors(),
+      self.scene.modifiers(),
+      self.scene.rotations(),
+    ] {
+      let mut row = to_packed_array(row);
+      row.resize(width);
+      data.extend_array(&row);
+    }
+
+    let image = Image::create_from_data(
+      width as i32,
+      SHAPE_DATA_ROWS,
+      false,
+      Format::RGBAF,
+      &data.to_byte_array(),
+    )
+    .unwrap();
+
+    self.shape_data_revision = Some(self.scene.revision());
+    return upload_image(&mut self.shape_data, &image);
+  }
 
-        return Ok(i);
+  /// The acceleration grid's cells for the renderer, one float per value
+  /// wrapped every `GRID_DATA_WIDTH` texels, rebuilt only when the grid was.
+  fn shape_grid(&mut self) -> Gd<ImageTexture> {
+    let built_for = (self.scene.layout_revision(), self.scene.blend_factor);
+    if let Some(shape_grid) = &self.shape_grid {
+      if self.shape_grid_built_for == Some(built_for) {
+        return shape_grid.clone();
       }
     }
 
-    return Err("Cannot allocate new shape, no shape slot available");
+    let mut cells: PackedFloat32Array = self
+      .scene
+      .grid()
+      .map_or(&[][..], |grid| grid.cells())
+      .iter()
+      .map(|cell| *cell as f32)
+      .collect();
+    let height = cells.len().div_ceil(GRID_DATA_WIDTH).max(1);
+    cells.resize(GRID_DATA_WIDTH * height);
+
+    let image = Image::create_from_data(
+      GRID_DATA_WIDTH as i32,
+      height as i32,
+      false,
+      Format::RF,
+      &cells.to_byte_array(),
+    )
+    .unwrap();
+
+    self.shape_grid_built_for = Some(built_for);
+    return upload_image(&mut self.shape_grid, &image);
   }
 
-  pub fn update_shape(
-    &mut self,
-    address: usize,
-    position: Vector4,
-    properties: Vector4,
-    color: Vector4,
-  ) {
-    self.positions[address] = position;
-    self.properties[address] = properties;
-    self.colors[address] = color;
+  /// The group instructions for the renderer, wrapped every
+  /// `INSTRUCTION_DATA_WIDTH` texels, rebuilt only when they were.
+  fn shape_instructions(&mut self) -> Gd<ImageTexture> {
+    if let Some(shape_instructions) = &self.shape_instructions {
+      if self.shape_instructions_revision == Some(self.scene.layout_revision()) {
+        return shape_instructions.clone();
+      }
+    }
+
+    let mut instructions = to_packed_array(self.scene.instructions().unwrap_or_default());
+    let height = instructions.len().div_ceil(INSTRUCTION_DATA_WIDTH).max(1);
+    instructions.resize(INSTRUCTION_DATA_WIDTH * height);
+
+    let image = Image::create_from_data(
+      INSTRUCTION_DATA_WIDTH as i32,
+      height as i32,
+      false,
+      Format::RGBAF,
+      &instructions.to_byte_array(),
+    )
+    .unwrap();
+
+    self.shape_instructions_revision = Some(self.scene.layout_revision());
+    return upload_image(&mut self.shape_instructions, &image);
   }
 
-  pub fn remove_shape(&mut self, address: usize) {
-    if !self.shapes_used[address] {
-      panic!("Shape double free");
+  /// The material table as a `MAX_MATERIALS` wide float texture for the
+  /// renderer, rebuilt only when a material changed.
+  fn material_data(&mut self) -> Gd<ImageTexture> {
+    let materials = self.scene.materials();
+    if let Some(material_data) = &self.material_data {
+      if self.material_data_revision == Some(materials.revision()) {
+        return material_data.clone();
+      }
+    }
+
+    let packed: Vec<[Vec4; 3]> = materials.materials().iter().map(Material::pack).collect();
+    let mut data = PackedVector4Array::new();
+    for row in 0..MATERIAL_DATA_ROWS as usize {
+      let mut row: PackedVector4Array = packed
+        .iter()
+        .map(|columns| to_vector4(columns[row]))
+        .collect();
+      row.resize(MAX_MATERIALS);
+      data.extend_array(&row);
     }
 
-    self.positions[address] = Vector4::ZERO;
-    self.properties[address] = Vector4::ZERO;
-    self.colors[address] = Vector4::ZERO;
-    self.shapes_used[address] = false;
-    self.num_shapes -= 1;
+    let image = Image::create_from_data(
+      MAX_MATERIALS as i32,
+      MATERIAL_DATA_ROWS,
+      false,
+      Format::RGBAF,
+      &data.to_byte_array(),
+    )
+    .unwrap();
+
+    self.material_data_revision = Some(materials.revision());
+    return upload_image(&mut self.material_data, &image);
   }
 
   // fn game_controller(&mut self) -> Gd<GameController> {
@@ -478,3 +867,35 @@ impl SdfController {
   //     .cast::<GameController>();
   // }
 }
+
+/// The map at `path` in either format, logging why it could not be read.
+fn read_map(path: &GString) -> Option<MapFile> {
+  if !FileAccess::file_exists(path) {
+    godot_error!("Failed to read map: {} does not exist", path);
+    return None;
+  }
+  let content = FileAccess::get_file_as_string(path).to_string();
+  match map_file::read(&path.to_string(), &content) {
+    Ok(map) => return Some(map),
+    Err(e) => {
+      godot_error!("Failed to read map: {}", e);
+      return None;
+    }
+  }
+}
+
+/// Copies `image` into `texture`, only reallocating it when the size changed.
+fn upload_image(texture: &mut Option<Gd<ImageTexture>>, image: &Gd<Image>) -> Gd<ImageTexture> {
+  if let Some(texture) = texture {
+    if texture.get_width() == image.get_width() && texture.get_height() == image.get_height() {
+      texture.update(image);
+    } else {
+      texture.set_image(image);
+    }
+    return texture.clone();
+  }
+
+  let created = ImageTexture::create_from_image(image).unwrap();
+  *texture = Some(created.clone());
+  return created;
+}
@@ -0,0 +1,113 @@
+use godot::prelude::*;
+use sdf_core::{map, Operation, SdfShape, ShapeFlags, ShapeKind, Vec3, Vec4};
+
+/// Converts a shape described by GDScript. The dictionary holds:
+/// - `kind`: a map file shape key like `"sphere"`
+/// - `position`: `Vector3`
+/// - `dimensions`: `Vector3`, laid out like the map file's `scale`
+/// - optionally `rotation` (`Quaternion`), `color` (`Color`), `flags` (the
+///   `SdfController.SHAPE_FLAG_*` constants), `rounding` (`float`),
+///   `operation` (`"union"`, `"subtract"` or `"intersect"`), `blend_radius`
+///   (`float`, the scene's blend factor when absent), `collision_layers`
+///   (`int`, the layer bits) and `material` (`int`, a material id)
+pub fn shape_from_dictionary(shape: &Dictionary) -> Result<SdfShape, &'static str> {
+  let kind: GString = required(shape, "kind")?;
+  let position: Vector3 = required(shape, "position")?;
+  let dimensions: Vector3 = required(shape, "dimensions")?;
+  let kind = map::shape_kind(&kind.to_string());
+  let kind = ShapeKind::unpack(from_vector3(dimensions).extend(kind))
+    .ok_or("Shape dictionary has an unknown kind")?;
+
+  let mut result = SdfShape::new(kind, from_vector3(position));
+  if let Some(rotation) = optional::<Quaternion>(shape, "rotation")? {
+    result = result.with_rotation(Vec4::new(rotation.x, rotation.y, rotation.z, rotation.w));
+  }
+  if let Some(color) = optional::<Color>(shape, "color")? {
+    result = result.with_color(Vec3::new(color.r, color.g, color.b));
+  }
+  if let Some(flags) = optional::<i64>(shape, "flags")? {
+    result = result.with_flags(ShapeFlags::from_bits_truncate(flags as u32));
+  }
+  if let Some(rounding) = optional::<f32>(shape, "rounding")? {
+    result = result.with_rounding(rounding);
+  }
+  if let Some(layers) = optional::<i64>(shape, "collision_layers")? {
+    result = result.with_collision_layers(layers as u32);
+  }
+  if let Some(material) = optional::<i64>(shape, "material")? {
+    result = result.with_material(material as u32);
+  }
+
+  let operation = match optional::<GString>(shape, "operation")? {
+    Some(name) => {
+      let name = name.to_string();
+      let (_, operation) = map::OPERATION_KEYS
+        .iter()
+        .find(|(key, _)| *key == name)
+        .ok_or("Shape dictionary has an unknown operation")?;
+      Operation::unpack(*operation).unwrap()
+    }
+    None => Operation::Union,
+  };
+  let blend_radius = optional::<f32>(shape, "blend_radius")?;
+  return Ok(result.with_operation(operation, blend_radius));
+}
+
+/// The dictionary `shape_from_dictionary` reads back into `shape`.
+pub fn shape_to_dictionary(shape: &SdfShape) -> Dictionary {
+  let properties = shape.kind.pack();
+  let operation = shape.operation.pack();
+  let mut dictionary = Dictionary::new();
+  dictionary.set("kind", map::shape_key(properties.w).unwrap_or_default());
+  dictionary.set("position", to_vector3(shape.position));
+  dictionary.set("dimensions", to_vector3(properties.xyz()));
+  dictionary.set(
+    "rotation",
+    Quaternion::new(
+      shape.rotation.x,
+      shape.rotation.y,
+      shape.rotation.z,
+      shape.rotation.w,
+    ),
+  );
+  dictionary.set(
+    "color",
+    Color::from_rgb(shape.color.x, shape.color.y, shape.color.z),
+  );
+  dictionary.set("flags", shape.flags.bits() as i64);
+  dictionary.set("rounding", shape.rounding);
+  dictionary.set("collision_layers", shape.collision_layers as i64);
+  dictionary.set("material", shape.material as i64);
+  if let Some((name, _)) = map::OPERATION_KEYS
+    .iter()
+    .find(|(_, key)| *key == operation)
+  {
+    dictionary.set("operation", *name);
+  }
+  if let Some(blend_radius) = shape.blend_radius {
+    dictionary.set("blend_radius", blend_radius);
+  }
+  return dictionary;
+}
+
+fn required<T: FromGodot>(shape: &Dictionary, key: &str) -> Result<T, &'static str> {
+  return optional(shape, key)?.ok_or("Shape dictionary is missing a field");
+}
+
+fn optional<T: FromGodot>(shape: &Dictionary, key: &str) -> Result<Option<T>, &'static str> {
+  let Some(value) = shape.get(key) else {
+    return Ok(None);
+  };
+  return value
+    .try_to::<T>()
+    .map(Some)
+    .map_err(|_| "Shape dictionary field has the wrong type");
+}
+
+fn from_vector3(vector: Vector3) -> Vec3 {
+  return Vec3::new(vector.x, vector.y, vector.z);
+}
+
+fn to_vector3(vector: Vec3) -> Vector3 {
+  return Vector3::new(vector.x, vector.y, vector.z);
+}
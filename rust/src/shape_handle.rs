@@ -0,0 +1,41 @@
+use godot::prelude::*;
+use sdf_core::scene;
+
+/// A shape of the `SdfController` as GDScript sees it. Opaque, and stale
+/// once the shape is removed even if another shape takes its place.
+#[derive(GodotClass)]
+#[class(no_init, base = RefCounted)]
+pub struct ShapeHandle {
+  handle: scene::ShapeHandle,
+}
+
+impl ShapeHandle {
+  pub fn from_handle(handle: scene::ShapeHandle) -> Gd<Self> {
+    return Gd::from_object(Self { handle });
+  }
+
+  pub fn handle(&self) -> scene::ShapeHandle {
+    return self.handle;
+  }
+}
+
+#[godot_api]
+impl IRefCounted for ShapeHandle {
+  fn to_string(&self) -> GString {
+    return format!(
+      "ShapeHandle({}:{})",
+      self.handle.slot(),
+      self.handle.generation()
+    )
+    .into();
+  }
+}
+
+#[godot_api]
+impl ShapeHandle {
+  /// Whether both handles refer to the same shape.
+  #[func]
+  fn is_same(&self, other: Gd<ShapeHandle>) -> bool {
+    return self.handle == other.bind().handle;
+  }
+}